@@ -11,6 +11,19 @@
     alert("Error: " + err.message);
   }
 
+  // Errors come back as application/problem+json, with the rejected fields
+  // of an invalid order listed in `errors`
+  function displayProblem(problem) {
+    let message = problem.detail || problem.title;
+    (problem.errors || []).forEach(error => {
+      message += "\n" + error.field + " " + error.reason;
+    });
+    if (problem.correlation_id) {
+      message += "\n\nReference: " + problem.correlation_id;
+    }
+    alert("Error: " + message);
+  }
+
   function onComputeButton() {
     const data = {
       order_id : parseFloat(orderIdField.value),
@@ -27,8 +40,13 @@
       body: JSON.stringify(data),
       headers: { "Content-type": "application/json" },
     })
-    .then(response => response.json())
-    .then(json => updateOrderForm(json))
+    .then(response => response.json().then(json => {
+      if (response.ok) {
+        updateOrderForm(json);
+      } else {
+        displayProblem(json);
+      }
+    }))
     .catch(err => displayError(err));
   }
 
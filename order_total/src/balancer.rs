@@ -0,0 +1,379 @@
+//! Spreads rate lookups over the replicas of the sales tax rate service,
+//! listed by `sales_tax_rate.endpoints` in the configuration. A replica
+//! that keeps failing, or fails a health check, is ejected for a while, and
+//! a failed lookup is retried on another replica.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use service_common::logging;
+use crate::config::{self, redact_url, CONFIG};
+
+/// How long a readiness probe waits on a replica that has not been health
+/// checked yet, so that the probe answers well within its own timeout.
+pub const COLD_START_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// How the next replica is picked.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Each replica in turn.
+    #[default]
+    RoundRobin,
+    /// The replica with the fewest lookups in flight.
+    LeastOutstanding,
+}
+
+/// How replicas are picked and ejected.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct BalancerSettings {
+    pub strategy: Strategy,
+    /// How many failures in a row eject a replica.
+    pub eject_after: u32,
+    /// How long a replica stays ejected.
+    #[serde(rename = "eject_ms", with = "config::millis")]
+    pub eject_for: Duration,
+    /// How often every replica is health checked, or zero for never.
+    #[serde(rename = "health_check_ms", with = "config::millis")]
+    pub health_check: Duration,
+}
+
+impl Default for BalancerSettings {
+    fn default() -> Self {
+        BalancerSettings {
+            strategy: Strategy::RoundRobin,
+            eject_after: 3,
+            eject_for: Duration::from_millis(10000),
+            health_check: Duration::from_millis(5000),
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref BALANCER_SETTINGS: &'static BalancerSettings = &CONFIG.sales_tax_rate.balancing;
+
+    /// The replicas of the sales tax rate service.
+    pub static ref BALANCER: Balancer = Balancer {
+        endpoints: CONFIG.sales_tax_rate.endpoints.iter().map(|url| Endpoint::new(url)).collect(),
+        next: AtomicUsize::new(0),
+    };
+}
+
+/// One replica of the sales tax rate service.
+pub struct Endpoint {
+    pub url: String,
+    /// The URL as logged and reported, without its password.
+    pub name: String,
+    outstanding: AtomicUsize,
+    consecutive_failures: AtomicU32,
+    ejected_until: Mutex<Option<Instant>>,
+    lookups: AtomicU64,
+    failures: AtomicU64,
+    /// The outcome of the latest health check, once there has been one.
+    last_check: Mutex<Option<Result<(), String>>>,
+}
+
+/// A replica's state, as reported by the status endpoint.
+#[derive(Serialize, Debug)]
+pub struct EndpointStatus<'a> {
+    pub url: &'a str,
+    pub ejected: bool,
+    pub outstanding: usize,
+    pub consecutive_failures: u32,
+    pub lookups: u64,
+    pub failures: u64,
+}
+
+impl Endpoint {
+    fn new(url: &str) -> Self {
+        Endpoint {
+            url: url.to_owned(),
+            name: redact_url(url),
+            outstanding: AtomicUsize::new(0),
+            consecutive_failures: AtomicU32::new(0),
+            ejected_until: Mutex::new(None),
+            lookups: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            last_check: Mutex::new(None),
+        }
+    }
+
+    fn is_ejected(&self) -> bool {
+        self.ejected_until.lock().unwrap().is_some_and(|until| Instant::now() < until)
+    }
+
+    fn eject(&self, why: &str) {
+        let mut ejected_until = self.ejected_until.lock().unwrap();
+        if !ejected_until.is_some_and(|until| Instant::now() < until) {
+            logging::warn("ejecting sales tax rate service replica", serde_json::json!({
+                "endpoint": self.name,
+                "eject_for_ms": logging::millis(BALANCER_SETTINGS.eject_for),
+                "reason": why,
+            }));
+        }
+        *ejected_until = Some(Instant::now() + BALANCER_SETTINGS.eject_for);
+    }
+
+    fn readmit(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        if self.ejected_until.lock().unwrap().take().is_some() {
+            logging::info("readmitting sales tax rate service replica", serde_json::json!({"endpoint": self.name}));
+        }
+    }
+
+    /// The URL health checks ask: the replica's readiness endpoint.
+    fn health_url(&self) -> String {
+        match reqwest::Url::parse(&self.url) {
+            Ok(mut url) => {
+                url.set_path("/readyz");
+                url.set_query(None);
+                url.to_string()
+            }
+            Err(_) => self.url.clone(),
+        }
+    }
+}
+
+/// Whether a replica answered a health check, as reported by the readiness
+/// endpoint.
+#[derive(Serialize, Debug)]
+pub struct EndpointHealth {
+    pub url: &'static str,
+    pub up: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The replicas and where round-robin is up to.
+pub struct Balancer {
+    endpoints: Vec<Endpoint>,
+    next: AtomicUsize,
+}
+
+/// A lookup in flight on a replica, counted until it is dropped.
+pub struct InFlight<'a> {
+    pub endpoint: &'a Endpoint,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.endpoint.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Balancer {
+    pub fn count(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    /// Picks the replica for a lookup, preferring one that is not ejected and
+    /// has not been `tried` for it yet. When every replica is ejected, one
+    /// is picked anyway rather than failing the lookup here.
+    pub fn pick(&self, tried: &[usize]) -> usize {
+        self.pick_by(BALANCER_SETTINGS.strategy, tried)
+    }
+
+    fn pick_by(&self, strategy: Strategy, tried: &[usize]) -> usize {
+        let n = self.endpoints.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % n;
+        let in_turn = || (0..n).map(move |i| (start + i) % n);
+        let healthy = |i: &usize| !self.endpoints[*i].is_ejected();
+        let untried = |i: &usize| !tried.contains(i);
+
+        let candidates: Vec<usize> = [
+            in_turn().filter(|i| healthy(i) && untried(i)).collect::<Vec<_>>(),
+            in_turn().filter(healthy).collect(),
+            in_turn().filter(untried).collect(),
+        ]
+            .into_iter()
+            .find(|candidates| !candidates.is_empty())
+            .unwrap_or_else(|| in_turn().collect());
+
+        match strategy {
+            Strategy::RoundRobin => candidates[0],
+            Strategy::LeastOutstanding => candidates.into_iter()
+                .min_by_key(|i| self.endpoints[*i].outstanding.load(Ordering::Relaxed))
+                .unwrap_or(start),
+        }
+    }
+
+    /// Starts a lookup on a replica.
+    pub fn begin(&self, i: usize) -> InFlight<'_> {
+        let endpoint = &self.endpoints[i];
+        endpoint.outstanding.fetch_add(1, Ordering::Relaxed);
+        endpoint.lookups.fetch_add(1, Ordering::Relaxed);
+        InFlight { endpoint }
+    }
+
+    /// Records a lookup the replica answered.
+    pub fn record_success(&self, i: usize) {
+        self.endpoints[i].readmit();
+    }
+
+    /// Records a lookup that failed because the replica is unhealthy,
+    /// ejecting it after too many in a row.
+    pub fn record_failure(&self, i: usize) {
+        let endpoint = &self.endpoints[i];
+        endpoint.failures.fetch_add(1, Ordering::Relaxed);
+        let failures = endpoint.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= BALANCER_SETTINGS.eject_after {
+            endpoint.eject(&format!("{} failed lookups in a row", failures));
+        }
+    }
+
+    pub fn status(&self) -> Vec<EndpointStatus<'_>> {
+        self.endpoints.iter()
+            .map(|endpoint| EndpointStatus {
+                url: &endpoint.name,
+                ejected: endpoint.is_ejected(),
+                outstanding: endpoint.outstanding.load(Ordering::Relaxed),
+                consecutive_failures: endpoint.consecutive_failures.load(Ordering::Relaxed),
+                lookups: endpoint.lookups.load(Ordering::Relaxed),
+                failures: endpoint.failures.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Every replica's health as of its latest check, or `None` before every
+    /// replica has had one. A replica ejected for failing lookups since it
+    /// passed its check is reported down.
+    pub fn health(&'static self) -> Option<Vec<EndpointHealth>> {
+        self.endpoints.iter()
+            .map(|endpoint| {
+                let error = match endpoint.last_check.lock().unwrap().clone()? {
+                    Err(error) => Some(error),
+                    Ok(()) if endpoint.is_ejected() => Some("ejected after failed lookups".to_owned()),
+                    Ok(()) => None,
+                };
+                Some(EndpointHealth { url: &endpoint.name, up: error.is_none(), error })
+            })
+            .collect()
+    }
+
+    /// Health checks every replica at once, waiting up to `timeout` on each,
+    /// ejecting the ones that are not ready and readmitting the ones that
+    /// are.
+    pub async fn check_health(&'static self, client: &reqwest::Client, timeout: Duration) -> Vec<EndpointHealth> {
+        let checks: Vec<_> = self.endpoints.iter()
+            .map(|endpoint| {
+                let checked = client.get(endpoint.health_url()).timeout(timeout).send();
+                tokio::spawn(async move { (endpoint, checked.await) })
+            })
+            .collect();
+
+        let mut health = Vec::with_capacity(checks.len());
+        for check in checks {
+            let Ok((endpoint, checked)) = check.await else { continue };
+            let error = match checked {
+                Ok(response) if !response.status().is_server_error() => None,
+                Ok(response) => Some(format!("health check answered {}", response.status())),
+                Err(e) => Some(format!("health check failed: {}", e.without_url())),
+            };
+            match &error {
+                None => endpoint.readmit(),
+                Some(error) => endpoint.eject(error),
+            }
+            *endpoint.last_check.lock().unwrap() = Some(error.clone().map_or(Ok(()), Err));
+            health.push(EndpointHealth { url: &endpoint.name, up: error.is_none(), error });
+        }
+        health
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balancer(replicas: usize) -> Balancer {
+        Balancer {
+            endpoints: (0..replicas).map(|i| Endpoint::new(&format!("http://replica-{}:8001/find_rate", i))).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn fail(balancer: &Balancer, i: usize, times: u32) {
+        for _ in 0..times {
+            balancer.record_failure(i);
+        }
+    }
+
+    #[test]
+    fn round_robin_takes_each_replica_in_turn() {
+        let balancer = balancer(3);
+        let picked: Vec<usize> = (0..6).map(|_| balancer.pick_by(Strategy::RoundRobin, &[])).collect();
+        assert_eq!(picked, [0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn a_retry_goes_to_a_replica_not_tried_yet() {
+        let balancer = balancer(3);
+        for _ in 0..6 {
+            let first = balancer.pick_by(Strategy::RoundRobin, &[]);
+            assert_ne!(balancer.pick_by(Strategy::RoundRobin, &[first]), first);
+        }
+    }
+
+    #[test]
+    fn least_outstanding_avoids_busy_replicas() {
+        let balancer = balancer(3);
+        let _busy = [balancer.begin(0), balancer.begin(0), balancer.begin(2)];
+        for _ in 0..3 {
+            assert_eq!(balancer.pick_by(Strategy::LeastOutstanding, &[]), 1);
+        }
+    }
+
+    #[test]
+    fn outstanding_lookups_are_counted_until_dropped() {
+        let balancer = balancer(1);
+        let lookup = balancer.begin(0);
+        assert_eq!(balancer.status()[0].outstanding, 1);
+        drop(lookup);
+        assert_eq!((balancer.status()[0].outstanding, balancer.status()[0].lookups), (0, 1));
+    }
+
+    #[test]
+    fn a_replica_is_ejected_after_enough_failures_in_a_row() {
+        let balancer = balancer(2);
+        fail(&balancer, 0, BALANCER_SETTINGS.eject_after - 1);
+        assert!(!balancer.status()[0].ejected);
+        fail(&balancer, 0, 1);
+        assert!(balancer.status()[0].ejected);
+        for _ in 0..4 {
+            assert_eq!(balancer.pick_by(Strategy::RoundRobin, &[]), 1);
+        }
+    }
+
+    #[test]
+    fn a_success_resets_the_failure_count_and_readmits() {
+        let balancer = balancer(2);
+        fail(&balancer, 0, BALANCER_SETTINGS.eject_after - 1);
+        balancer.record_success(0);
+        fail(&balancer, 0, 1);
+        assert!(!balancer.status()[0].ejected);
+
+        fail(&balancer, 0, BALANCER_SETTINGS.eject_after);
+        assert!(balancer.status()[0].ejected);
+        balancer.record_success(0);
+        assert!(!balancer.status()[0].ejected);
+        assert_eq!(balancer.status()[0].consecutive_failures, 0);
+    }
+
+    #[test]
+    fn an_ejected_replica_is_still_picked_when_every_replica_is() {
+        let balancer = balancer(2);
+        fail(&balancer, 0, BALANCER_SETTINGS.eject_after);
+        fail(&balancer, 1, BALANCER_SETTINGS.eject_after);
+        let picked: Vec<usize> = (0..2).map(|_| balancer.pick_by(Strategy::RoundRobin, &[])).collect();
+        assert_eq!(picked, [0, 1]);
+        assert_eq!(balancer.pick_by(Strategy::RoundRobin, &[0]), 1);
+    }
+
+    #[test]
+    fn a_healthy_replica_already_tried_is_picked_over_an_ejected_one() {
+        let balancer = balancer(2);
+        fail(&balancer, 1, BALANCER_SETTINGS.eject_after);
+        assert_eq!(balancer.pick_by(Strategy::RoundRobin, &[0]), 0);
+    }
+}
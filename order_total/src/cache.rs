@@ -0,0 +1,417 @@
+//! An in-process cache of rate lookups, so that orders for the same hot zip
+//! codes do not each make a round trip to the sales tax rate service.
+//!
+//! A rate is fresh for the TTL. For a while after that it is stale: it is
+//! still served, and refreshed in the background, so that an order is not
+//! held up by (or failed because of) a slow or failing rate service. A zip
+//! code with no rate on file is remembered for a shorter TTL.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use crate::config::{self, CONFIG};
+use crate::request_log::RequestLog;
+use crate::upstream::{self, RateFailure, RateLookup, RateRequest};
+
+/// How the cache keeps rates.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheSettings {
+    /// The most lookups kept. Zero turns the cache off.
+    pub max_entries: usize,
+    /// How long a rate is fresh.
+    #[serde(rename = "ttl_ms", with = "config::millis")]
+    pub ttl: Duration,
+    /// How long a zip code with no rate on file is remembered.
+    #[serde(rename = "negative_ttl_ms", with = "config::millis")]
+    pub negative_ttl: Duration,
+    /// How long after going stale a rate may still be served while it is
+    /// being refreshed.
+    #[serde(rename = "stale_ms", with = "config::millis")]
+    pub stale: Duration,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            max_entries: 10000,
+            ttl: Duration::from_millis(300000),
+            negative_ttl: Duration::from_millis(30000),
+            stale: Duration::from_millis(3600000),
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref SETTINGS: &'static CacheSettings = &CONFIG.cache;
+
+    pub static ref CACHE: RateCache = RateCache::default();
+}
+
+/// What a cached lookup is for. The zip code is normalized, so `787011234`
+/// and `78701-1234` share an entry, and the date is the one the rate is
+/// effective on.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct Key {
+    zip: String,
+    date: NaiveDate,
+    product_id: i32,
+    category: Option<String>,
+}
+
+impl Key {
+    fn new(request: &RateRequest<'_>) -> Self {
+        Key {
+            zip: normalize_zip(request.zip),
+            date: request.date.unwrap_or_else(today),
+            product_id: request.product_id,
+            category: request.category.map(str::to_owned),
+        }
+    }
+}
+
+enum Cached {
+    Found(RateLookup),
+    /// The zip code has no rate on file.
+    NotFound,
+}
+
+struct Entry {
+    cached: Cached,
+    fetched_at: Instant,
+    refreshing: bool,
+}
+
+/// Whether an entry may be served.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Freshness {
+    Fresh,
+    /// Served while it is refreshed.
+    Stale,
+    Expired,
+}
+
+impl Entry {
+    /// Whether the entry may be served `age` after it was fetched. A zip
+    /// code with no rate on file is never served stale.
+    fn freshness(&self, age: Duration) -> Freshness {
+        let (ttl, stale) = match self.cached {
+            Cached::Found(_) => (SETTINGS.ttl, SETTINGS.stale),
+            Cached::NotFound => (SETTINGS.negative_ttl, Duration::ZERO),
+        };
+        if age < ttl {
+            Freshness::Fresh
+        } else if age < ttl + stale {
+            Freshness::Stale
+        } else {
+            Freshness::Expired
+        }
+    }
+
+    /// Whether the entry may still be served, fresh or stale.
+    fn is_usable(&self) -> bool {
+        self.freshness(self.fetched_at.elapsed()) != Freshness::Expired
+    }
+
+    fn result(&self) -> Result<RateLookup, RateFailure> {
+        match &self.cached {
+            Cached::Found(lookup) => Ok(lookup.clone()),
+            Cached::NotFound => Err(RateFailure::NotFound),
+        }
+    }
+}
+
+/// The cache's hit and miss counts, as reported by the status endpoint.
+#[derive(Serialize, Default, Debug)]
+pub struct CacheStats {
+    pub entries: usize,
+    /// Lookups answered with a fresh rate.
+    pub hits: u64,
+    /// Lookups answered from a remembered zip code with no rate on file.
+    pub negative_hits: u64,
+    /// Lookups answered with a stale rate while it was refreshed.
+    pub stale_hits: u64,
+    /// Lookups that had to wait for the rate service.
+    pub misses: u64,
+    /// Background refreshes that failed, leaving the stale rate in place.
+    pub failed_refreshes: u64,
+    /// Entries dropped to keep the cache within its size.
+    pub evictions: u64,
+}
+
+#[derive(Default)]
+pub struct RateCache {
+    entries: Mutex<HashMap<Key, Entry>>,
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    stale_hits: AtomicU64,
+    misses: AtomicU64,
+    failed_refreshes: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl RateCache {
+    /// Looks up a rate through the cache.
+    pub async fn fetch_rate(&'static self, client: &reqwest::Client, request: &RateRequest<'_>, request_log: &RequestLog) -> Result<RateLookup, RateFailure> {
+        if SETTINGS.max_entries == 0 {
+            return upstream::fetch_rate(client, request, request_log).await;
+        }
+
+        // Ask for the rate on the date it is cached under, even when that is
+        // today, so that the key always matches what was looked up
+        let key = Key::new(request);
+        let request = RateRequest::new(request.zip, Some(key.date), request.product_id, request.category);
+
+        if let Some(result) = self.cached(&key, client, request_log) {
+            return result;
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let result = upstream::fetch_rate(client, &request, request_log).await;
+        self.store(key, &result);
+        result
+    }
+
+    /// Answers from the cache when it can, starting a background refresh of
+    /// a stale rate.
+    fn cached(&'static self, key: &Key, client: &reqwest::Client, request_log: &RequestLog) -> Option<Result<RateLookup, RateFailure>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(key)?;
+        let freshness = entry.freshness(entry.fetched_at.elapsed());
+        if freshness == Freshness::Expired {
+            return None;
+        }
+
+        if freshness == Freshness::Fresh {
+            let counter = match entry.cached {
+                Cached::Found(_) => &self.hits,
+                Cached::NotFound => &self.negative_hits,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+            return Some(entry.result());
+        }
+
+        self.stale_hits.fetch_add(1, Ordering::Relaxed);
+        if !entry.refreshing {
+            entry.refreshing = true;
+            let key = key.clone();
+            let client = client.clone();
+            // The refresh outlives the request, so its calls are not counted
+            // in the request's log line
+            let request_log = RequestLog::new(request_log.correlation_id.clone(), request_log.trace.clone());
+            tokio::spawn(async move {
+                let request = RateRequest::new(&key.zip, Some(key.date), key.product_id, key.category.as_deref());
+                let result = upstream::fetch_rate(&client, &request, &request_log).await;
+                self.refreshed(key, &result);
+            });
+        }
+        Some(entry.result())
+    }
+
+    /// Stores the outcome of a background refresh. A refresh that failed
+    /// leaves the stale rate to be served until it is too old.
+    fn refreshed(&self, key: Key, result: &Result<RateLookup, RateFailure>) {
+        if matches!(result, Ok(_) | Err(RateFailure::NotFound)) {
+            self.store(key, result);
+        } else {
+            self.failed_refreshes.fetch_add(1, Ordering::Relaxed);
+            if let Some(entry) = self.entries.lock().unwrap().get_mut(&key) {
+                entry.refreshing = false;
+            }
+        }
+    }
+
+    /// Remembers a found rate, or a zip code with no rate on file. Any other
+    /// failure is not cached.
+    fn store(&self, key: Key, result: &Result<RateLookup, RateFailure>) {
+        let cached = match result {
+            Ok(lookup) => Cached::Found(lookup.clone()),
+            Err(RateFailure::NotFound) => Cached::NotFound,
+            Err(_) => return,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(&key) && entries.len() >= SETTINGS.max_entries {
+            // Make room by dropping what can no longer be served, and failing
+            // that the oldest entry
+            let before = entries.len();
+            entries.retain(|_, entry| entry.is_usable());
+            if entries.len() >= SETTINGS.max_entries {
+                let oldest = entries.iter()
+                    .min_by_key(|(_, entry)| entry.fetched_at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+            self.evictions.fetch_add((before - entries.len()) as u64, Ordering::Relaxed);
+        }
+        entries.insert(key, Entry { cached, fetched_at: Instant::now(), refreshing: false });
+    }
+
+    /// The last rate looked up for a request, however old, and its age.
+    pub fn last_known(&self, request: &RateRequest<'_>) -> Option<(RateLookup, Duration)> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(&Key::new(request))? {
+            Entry { cached: Cached::Found(lookup), fetched_at, .. } => Some((lookup.clone(), fetched_at.elapsed())),
+            Entry { cached: Cached::NotFound, .. } => None,
+        }
+    }
+
+    /// The category on file for a product, as an earlier lookup for it
+    /// resolved it: `None` when it was never looked up without a category,
+    /// and `Some(None)` when it has no category on file.
+    pub fn category_of(&self, product_id: i32) -> Option<Option<String>> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().find_map(|(key, entry)| match &entry.cached {
+            Cached::Found(lookup) if key.product_id == product_id && key.category.is_none() => Some(lookup.category.clone()),
+            _ => None,
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.lock().unwrap().len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            negative_hits: self.negative_hits.load(Ordering::Relaxed),
+            stale_hits: self.stale_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            failed_refreshes: self.failed_refreshes.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Writes a zip code the way the rate service reads it: trimmed, with a
+/// ZIP+4 code as `NNNNN-NNNN`.
+fn normalize_zip(zip: &str) -> String {
+    let zip = zip.trim();
+    if zip.len() == 9 && zip.bytes().all(|b| b.is_ascii_digit()) {
+        format!("{}-{}", &zip[..5], &zip[5..])
+    } else {
+        zip.to_owned()
+    }
+}
+
+/// Today's date in UTC, which is what the rate service looks rates up for
+/// when not given a date.
+fn today() -> NaiveDate {
+    DateTime::<Utc>::from(SystemTime::now()).date_naive()
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+    use service_common::trace::TraceContext;
+    use super::*;
+
+    fn request(zip: &str) -> RateRequest<'_> {
+        RateRequest::new(zip, NaiveDate::from_ymd_opt(2024, 1, 1), 321, None)
+    }
+
+    fn found() -> Result<RateLookup, RateFailure> {
+        Ok(RateLookup { category: None, rate: Decimal::new(825, 4), jurisdictions: Vec::new(), table_version: None })
+    }
+
+    fn entry(result: Result<RateLookup, RateFailure>) -> Entry {
+        let cached = match result {
+            Ok(lookup) => Cached::Found(lookup),
+            Err(_) => Cached::NotFound,
+        };
+        Entry { cached, fetched_at: Instant::now(), refreshing: false }
+    }
+
+    fn cache() -> &'static RateCache {
+        Box::leak(Box::default())
+    }
+
+    fn request_log() -> RequestLog {
+        RequestLog::new("test".into(), TraceContext { trace_id: 1, span_id: 1, sampled: false, state: None })
+    }
+
+    #[test]
+    fn a_rate_is_fresh_for_its_ttl_then_stale_then_expired() {
+        let entry = entry(found());
+        let millisecond = Duration::from_millis(1);
+        assert_eq!(entry.freshness(Duration::ZERO), Freshness::Fresh);
+        assert_eq!(entry.freshness(SETTINGS.ttl - millisecond), Freshness::Fresh);
+        assert_eq!(entry.freshness(SETTINGS.ttl), Freshness::Stale);
+        assert_eq!(entry.freshness(SETTINGS.ttl + SETTINGS.stale - millisecond), Freshness::Stale);
+        assert_eq!(entry.freshness(SETTINGS.ttl + SETTINGS.stale), Freshness::Expired);
+    }
+
+    #[test]
+    fn a_zip_code_with_no_rate_is_remembered_briefly_and_never_stale() {
+        let entry = entry(Err(RateFailure::NotFound));
+        assert_eq!(entry.freshness(SETTINGS.negative_ttl - Duration::from_millis(1)), Freshness::Fresh);
+        assert_eq!(entry.freshness(SETTINGS.negative_ttl), Freshness::Expired);
+    }
+
+    #[test]
+    fn only_rates_and_zip_codes_with_no_rate_are_stored() {
+        let cache = cache();
+        cache.store(Key::new(&request("78701")), &found());
+        cache.store(Key::new(&request("00000")), &Err(RateFailure::NotFound));
+        cache.store(Key::new(&request("78702")), &Err(RateFailure::Unavailable));
+        cache.store(Key::new(&request("78703")), &Err(RateFailure::Timeout));
+        assert_eq!(cache.stats().entries, 2);
+        assert!(cache.last_known(&request("78701")).is_some());
+        assert!(cache.last_known(&request("00000")).is_none());
+        assert!(cache.last_known(&request("78702")).is_none());
+    }
+
+    #[test]
+    fn fresh_entries_are_answered_from_the_cache() {
+        let cache = cache();
+        let client = reqwest::Client::new();
+        cache.store(Key::new(&request("78701")), &found());
+        cache.store(Key::new(&request("00000")), &Err(RateFailure::NotFound));
+
+        let hit = cache.cached(&Key::new(&request("78701")), &client, &request_log());
+        assert_eq!(hit.unwrap().unwrap().rate, Decimal::new(825, 4));
+        let negative_hit = cache.cached(&Key::new(&request("00000")), &client, &request_log());
+        assert!(matches!(negative_hit, Some(Err(RateFailure::NotFound))));
+        assert!(cache.cached(&Key::new(&request("78702")), &client, &request_log()).is_none());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.negative_hits, stats.stale_hits), (1, 1, 0));
+    }
+
+    #[test]
+    fn a_failed_refresh_keeps_the_stale_rate() {
+        let cache = cache();
+        let key = Key::new(&request("78701"));
+        cache.store(key.clone(), &found());
+        cache.entries.lock().unwrap().get_mut(&key).unwrap().refreshing = true;
+
+        cache.refreshed(key.clone(), &Err(RateFailure::Timeout));
+        assert!(cache.last_known(&request("78701")).is_some());
+        assert!(!cache.entries.lock().unwrap()[&key].refreshing);
+        assert_eq!(cache.stats().failed_refreshes, 1);
+
+        cache.refreshed(key.clone(), &Err(RateFailure::NotFound));
+        assert!(cache.last_known(&request("78701")).is_none());
+    }
+
+    #[test]
+    fn the_category_on_file_of_a_product_is_known_once_looked_up_without_one() {
+        let cache = cache();
+        assert_eq!(cache.category_of(321), None);
+        let grocery = RateLookup { category: Some("grocery".into()), ..found().unwrap() };
+        cache.store(Key::new(&RateRequest::new("78701", None, 321, Some("grocery"))), &Ok(grocery.clone()));
+        assert_eq!(cache.category_of(321), None);
+        cache.store(Key::new(&request("78701")), &Ok(grocery));
+        assert_eq!(cache.category_of(321), Some(Some("grocery".into())));
+        cache.store(Key::new(&RateRequest::new("78701", None, 654, None)), &found());
+        assert_eq!(cache.category_of(654), Some(None));
+    }
+
+    #[test]
+    fn zip_plus4_codes_share_an_entry_however_they_are_written() {
+        assert_eq!(Key::new(&request("787011234")), Key::new(&request(" 78701-1234 ")));
+        assert_ne!(Key::new(&request("78701")), Key::new(&request("78701-1234")));
+    }
+}
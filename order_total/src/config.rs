@@ -0,0 +1,235 @@
+//! The service's configuration. Every setting has a default, which a TOML
+//! file named by `ORDER_TOTAL_CONFIG` may change, and an environment
+//! variable may change again. It is all checked at startup, so a bad
+//! setting stops the service instead of failing orders later.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use service_common::config::{self as common, AdminConfig, CorsConfig, LogConfig};
+use service_common::trace::TracingSettings;
+use crate::balancer::BalancerSettings;
+use crate::cache::CacheSettings;
+use crate::fallback::{self, Source};
+use crate::money::Rounding;
+use crate::orders::OrderStoreSettings;
+use crate::resilience::Settings;
+use crate::upstream::PoolSettings;
+
+pub use service_common::config::{millis, redact_url};
+
+/// The environment variable naming the configuration file.
+const CONFIG_FILE: &str = "ORDER_TOTAL_CONFIG";
+
+/// The environment variables that override settings, and the settings'
+/// keys in the configuration file. A list is separated by commas, and a
+/// table is written as `KEY=VALUE` pairs.
+const OVERRIDES: &[(&str, &str)] = &[
+    ("ORDER_TOTAL_BIND", "server.bind"),
+    ("ORDER_TOTAL_PORT", "server.port"),
+    ("ORDER_TOTAL_DRAIN_MS", "server.drain_ms"),
+    ("ORDER_TOTAL_CORS_ORIGINS", "cors.allowed_origins"),
+    ("ORDER_TOTAL_ADMIN_TOKEN", "admin.token"),
+    ("ORDER_TOTAL_LOG_LEVEL", "log.level"),
+    ("ORDER_TOTAL_TRACING_EXPORTER", "tracing.exporter"),
+    ("ORDER_TOTAL_TRACING_FILE", "tracing.file"),
+    ("ORDER_TOTAL_TRACING_OTLP_ENDPOINT", "tracing.otlp_endpoint"),
+    ("ORDER_TOTAL_TRACING_FLUSH_MS", "tracing.flush_ms"),
+    ("SALES_TAX_RATE_SERVICE", "sales_tax_rate.endpoints"),
+    ("SALES_TAX_RATE_CONNECT_TIMEOUT_MS", "sales_tax_rate.calls.connect_timeout_ms"),
+    ("SALES_TAX_RATE_TIMEOUT_MS", "sales_tax_rate.calls.timeout_ms"),
+    ("SALES_TAX_RATE_RETRIES", "sales_tax_rate.calls.retries"),
+    ("SALES_TAX_RATE_BACKOFF_MS", "sales_tax_rate.calls.backoff_ms"),
+    ("SALES_TAX_RATE_MAX_BACKOFF_MS", "sales_tax_rate.calls.max_backoff_ms"),
+    ("SALES_TAX_RATE_BREAKER_THRESHOLD", "sales_tax_rate.calls.breaker_threshold"),
+    ("SALES_TAX_RATE_BREAKER_COOLDOWN_MS", "sales_tax_rate.calls.breaker_cooldown_ms"),
+    ("SALES_TAX_RATE_POOL_MAX_IDLE", "sales_tax_rate.pool.max_idle"),
+    ("SALES_TAX_RATE_POOL_IDLE_TIMEOUT_MS", "sales_tax_rate.pool.idle_timeout_ms"),
+    ("SALES_TAX_RATE_TCP_KEEPALIVE_MS", "sales_tax_rate.pool.keepalive_ms"),
+    ("SALES_TAX_RATE_BALANCING", "sales_tax_rate.balancing.strategy"),
+    ("SALES_TAX_RATE_EJECT_AFTER", "sales_tax_rate.balancing.eject_after"),
+    ("SALES_TAX_RATE_EJECT_MS", "sales_tax_rate.balancing.eject_ms"),
+    ("SALES_TAX_RATE_HEALTH_CHECK_MS", "sales_tax_rate.balancing.health_check_ms"),
+    ("SALES_TAX_RATE_CACHE_MAX_ENTRIES", "cache.max_entries"),
+    ("SALES_TAX_RATE_CACHE_TTL_MS", "cache.ttl_ms"),
+    ("SALES_TAX_RATE_CACHE_NEGATIVE_TTL_MS", "cache.negative_ttl_ms"),
+    ("SALES_TAX_RATE_CACHE_STALE_MS", "cache.stale_ms"),
+    ("ORDER_ROUNDING", "tax.rounding"),
+    ("ORDER_TAX_FALLBACK", "tax.fallback"),
+    ("ORDER_TAX_FALLBACK_RATES", "tax.fallback_rates"),
+    ("ORDER_TAX_FALLBACK_EXCLUDED_CATEGORIES", "tax.fallback_excluded_categories"),
+    ("ORDER_MAX_BODY_BYTES", "compute.max_body_bytes"),
+    ("ORDER_MAX_LINE_ITEMS", "compute.max_line_items"),
+    ("ORDER_MAX_CONCURRENT_LOOKUPS", "compute.max_concurrent_lookups"),
+    ("ORDER_STORE_FILE", "orders.file"),
+    ("ORDER_STORE_MAX_PAGE_SIZE", "orders.max_page_size"),
+];
+
+lazy_static! {
+    pub static ref CONFIG: Config = Config::load().unwrap_or_else(|e| panic!("Invalid configuration: {:#}", e));
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub cors: CorsConfig,
+    pub admin: AdminConfig,
+    pub log: LogConfig,
+    pub tracing: TracingSettings,
+    pub sales_tax_rate: RateServiceConfig,
+    pub cache: CacheSettings,
+    pub tax: TaxConfig,
+    pub compute: ComputeConfig,
+    pub orders: OrderStoreSettings,
+}
+
+/// Where the service listens.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// How long requests in flight may take to finish once shutting down
+    /// starts.
+    #[serde(rename = "drain_ms", with = "millis")]
+    pub drain: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED), port: 8002, drain: Duration::from_millis(30000) }
+    }
+}
+
+impl ServerConfig {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+}
+
+/// How the sales tax rate service is called.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateServiceConfig {
+    /// The `/find_rate` URL of every replica.
+    pub endpoints: Vec<String>,
+    pub calls: Settings,
+    pub pool: PoolSettings,
+    pub balancing: BalancerSettings,
+}
+
+impl Default for RateServiceConfig {
+    fn default() -> Self {
+        RateServiceConfig {
+            endpoints: vec!["http://localhost:8001/find_rate".into()],
+            calls: Settings::default(),
+            pool: PoolSettings::default(),
+            balancing: BalancerSettings::default(),
+        }
+    }
+}
+
+/// How tax is worked out.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TaxConfig {
+    pub rounding: Rounding,
+    /// Where tax is estimated from, in order, when the sales tax rate
+    /// service cannot be asked. Empty turns estimates off.
+    pub fallback: Vec<Source>,
+    /// Rates to estimate with, by state, by zip code prefix, and `*` for
+    /// anywhere else.
+    pub fallback_rates: BTreeMap<String, Decimal>,
+    /// Categories some jurisdiction exempts or taxes at a reduced rate. A
+    /// configured rate is a full rate, so it is never used for them.
+    pub fallback_excluded_categories: Vec<String>,
+}
+
+impl Default for TaxConfig {
+    fn default() -> Self {
+        TaxConfig {
+            rounding: Rounding::default(),
+            fallback: Vec::new(),
+            fallback_rates: BTreeMap::new(),
+            fallback_excluded_categories: vec!["digital".into(), "grocery".into(), "medicine".into()],
+        }
+    }
+}
+
+/// How large an order `/compute` takes, and how it looks up its rates.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ComputeConfig {
+    /// The largest body `/compute` reads.
+    pub max_body_bytes: usize,
+    /// The most line items an order may hold.
+    pub max_line_items: usize,
+    /// How many of an order's rate lookups may be in flight at once.
+    pub max_concurrent_lookups: usize,
+}
+
+impl Default for ComputeConfig {
+    fn default() -> Self {
+        ComputeConfig { max_body_bytes: 1_048_576, max_line_items: 100, max_concurrent_lookups: 8 }
+    }
+}
+
+impl Config {
+    fn load() -> anyhow::Result<Config> {
+        common::load(CONFIG_FILE, OVERRIDES, Config::check)
+    }
+
+    /// Adds a problem for every setting that has the right type but a value
+    /// that cannot work.
+    fn check(&self, problems: &mut Vec<String>) {
+        if self.server.port == 0 {
+            problems.push("server.port must not be 0".into());
+        }
+        self.cors.check(problems);
+        self.admin.check(problems);
+        self.tracing.check(problems);
+
+        let rate_service = &self.sales_tax_rate;
+        if rate_service.endpoints.is_empty() {
+            problems.push("sales_tax_rate.endpoints must list at least one endpoint".into());
+        }
+        for endpoint in &rate_service.endpoints {
+            // The client is built without TLS, so an https endpoint could never be reached
+            let valid = reqwest::Url::parse(endpoint).is_ok_and(|url| url.scheme() == "http" && url.has_host());
+            if !valid {
+                problems.push(format!("sales_tax_rate.endpoints must be http URLs, not {:?}", redact_url(endpoint)));
+            }
+        }
+        if rate_service.calls.connect_timeout.is_zero() || rate_service.calls.timeout.is_zero() {
+            problems.push("sales_tax_rate.calls timeouts must not be 0".into());
+        }
+        if rate_service.calls.breaker_threshold == 0 {
+            problems.push("sales_tax_rate.calls.breaker_threshold must be at least 1".into());
+        }
+        if rate_service.balancing.eject_after == 0 {
+            problems.push("sales_tax_rate.balancing.eject_after must be at least 1".into());
+        }
+
+        for (place, rate) in &self.tax.fallback_rates {
+            if !fallback::is_place(place) {
+                problems.push(format!("tax.fallback_rates must be by state, zip code prefix or *, not {:?}", place));
+            }
+            if *rate < Decimal::ZERO || *rate > Decimal::ONE {
+                problems.push(format!("tax.fallback_rates.{} must be between 0 and 1", place));
+            }
+        }
+        if self.tax.fallback.contains(&Source::Configured) && self.tax.fallback_rates.is_empty() {
+            problems.push("tax.fallback uses configured rates, but tax.fallback_rates is empty".into());
+        }
+        let compute = &self.compute;
+        if compute.max_body_bytes == 0 || compute.max_line_items == 0 || compute.max_concurrent_lookups == 0 {
+            problems.push("compute.max_body_bytes, max_line_items and max_concurrent_lookups must be at least 1".into());
+        }
+        if self.orders.max_page_size == 0 {
+            problems.push("orders.max_page_size must be at least 1".into());
+        }
+    }
+}
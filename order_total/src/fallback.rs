@@ -0,0 +1,200 @@
+//! Degraded mode: when the sales tax rate service cannot be asked, tax can
+//! be estimated instead of failing the order. This is opt-in, since an
+//! estimate may be wrong; an estimated order says so, and why, so that it
+//! can be checked again later.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use crate::cache;
+use crate::config::CONFIG;
+use crate::upstream::{RateFailure, RateLookup, RateRequest};
+
+/// The first three digits of zip codes and the state they are in, compiled
+/// into the binary.
+const ZIP_PREFIX_STATES: &str = include_str!("zip_prefix_states.csv");
+
+/// Where an estimated rate may come from.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// The last rate looked up for the same zip code, product and date,
+    /// however old.
+    LastKnown,
+    /// The rate configured for the zip code's prefix or state.
+    Configured,
+}
+
+lazy_static! {
+    /// The sources tried, in order. Empty, the default, turns estimates off.
+    pub static ref POLICY: &'static [Source] = &CONFIG.tax.fallback;
+
+    /// The configured fallback rates, such as `TX = 0.0825`, `787 = 0.0825`
+    /// and `"*" = 0.07`: by state, by zip code prefix, and for anywhere else.
+    pub static ref RATES: &'static BTreeMap<String, Decimal> = &CONFIG.tax.fallback_rates;
+
+    /// The categories configured rates are never used for.
+    static ref EXCLUDED_CATEGORIES: &'static [String] = &CONFIG.tax.fallback_excluded_categories;
+
+    static ref STATES: Vec<(u16, u16, String)> = ZIP_PREFIX_STATES.lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split(',');
+            let from = fields.next()?.parse().ok()?;
+            let to = fields.next()?.parse().ok()?;
+            Some((from, to, fields.next()?.to_owned()))
+        })
+        .collect();
+}
+
+/// Whether a fallback rate's place is a state, a zip code prefix or `*`.
+pub fn is_place(place: &str) -> bool {
+    let is_state = place.len() == 2 && place.bytes().all(|b| b.is_ascii_uppercase());
+    let is_prefix = (1..=5).contains(&place.len()) && place.bytes().all(|b| b.is_ascii_digit());
+    is_state || is_prefix || place == "*"
+}
+
+/// A rate to estimate tax with, and why it had to be estimated.
+pub struct Estimate {
+    pub lookup: RateLookup,
+    pub reason: String,
+}
+
+/// Estimates the rate for a lookup that failed, when the policy allows it.
+/// Only a rate service that could not answer is worked around: a zip code
+/// it says has no rate still fails the order.
+pub fn estimate(request: &RateRequest<'_>, failure: &RateFailure) -> Option<Estimate> {
+    if !failure.is_transient() && !matches!(failure, RateFailure::CircuitOpen) {
+        return None;
+    }
+    let cause = match failure {
+        RateFailure::Unavailable => "The sales tax rate service could not be reached",
+        RateFailure::Timeout => "The sales tax rate service did not answer in time",
+        RateFailure::CircuitOpen => "The sales tax rate service is failing and lookups are paused",
+        _ => "The sales tax rate service failed",
+    };
+
+    POLICY.iter().find_map(|source| match source {
+        Source::LastKnown => {
+            let (lookup, age) = cache::CACHE.last_known(request)?;
+            let reason = format!("{}, so the rate looked up {} ago was used", cause, describe_age(age));
+            Some(Estimate { lookup, reason })
+        }
+        Source::Configured => {
+            let category = configured_category(request)?;
+            let (place, rate) = configured_rate(request.zip, &RATES)?;
+            let place = if place == "*" { "any zip code".to_owned() } else { place };
+            let reason = format!("{}, so the fallback rate configured for {} was used", cause, place);
+            let lookup = RateLookup { category, rate, jurisdictions: Vec::new(), table_version: None };
+            Some(Estimate { lookup, reason })
+        }
+    })
+}
+
+/// The category a lookup is taxed under when a configured rate may tax it:
+/// the one asked for, otherwise the product's category on file as an
+/// earlier lookup resolved it. A product never looked up may be exempt, so
+/// it is not estimated, and neither are excluded categories.
+fn configured_category(request: &RateRequest<'_>) -> Option<Option<String>> {
+    let category = match request.category {
+        Some(category) => Some(category.to_owned()),
+        None => cache::CACHE.category_of(request.product_id)?,
+    };
+    let excluded = category.as_ref().is_some_and(|category| EXCLUDED_CATEGORIES.contains(category));
+    (!excluded).then_some(category)
+}
+
+/// The configured rate for a zip code: the one for its longest configured
+/// prefix, otherwise its state's, otherwise the catch-all.
+fn configured_rate(zip: &str, rates: &BTreeMap<String, Decimal>) -> Option<(String, Decimal)> {
+    let zip = zip.trim();
+    let by_prefix = rates.iter()
+        .filter(|(place, _)| place.bytes().all(|b| b.is_ascii_digit()) && zip.starts_with(place.as_str()))
+        .max_by_key(|(place, _)| place.len());
+    let by_state = || state_of(zip).and_then(|state| rates.get_key_value(state));
+    let anywhere = || rates.get_key_value("*");
+    by_prefix.or_else(by_state).or_else(anywhere).map(|(place, rate)| (place.clone(), *rate))
+}
+
+/// The state a zip code is in, from its first three digits.
+fn state_of(zip: &str) -> Option<&'static str> {
+    let prefix: u16 = zip.get(..3)?.parse().ok()?;
+    STATES.iter()
+        .find(|(from, to, _)| (*from..=*to).contains(&prefix))
+        .map(|(_, _, state)| state.as_str())
+}
+
+fn describe_age(age: Duration) -> String {
+    let (count, unit) = match age.as_secs() {
+        secs @ 0..=119 => (secs, "second"),
+        secs @ 120..=7199 => (secs / 60, "minute"),
+        secs => (secs / 3600, "hour"),
+    };
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rates(places: &[(&str, &str)]) -> BTreeMap<String, Decimal> {
+        places.iter().map(|(place, rate)| (place.to_string(), rate.parse().unwrap())).collect()
+    }
+
+    fn rate(zip: &str, rates: &BTreeMap<String, Decimal>) -> Option<(String, String)> {
+        configured_rate(zip, rates).map(|(place, rate)| (place, rate.to_string()))
+    }
+
+    fn found(place: &str, rate: &str) -> Option<(String, String)> {
+        Some((place.to_owned(), rate.to_owned()))
+    }
+
+    #[test]
+    fn the_longest_configured_prefix_wins() {
+        let rates = rates(&[("7", "0.05"), ("787", "0.0825"), ("78701", "0.09"), ("TX", "0.0625"), ("*", "0.07")]);
+        assert_eq!(rate("78701", &rates), found("78701", "0.09"));
+        assert_eq!(rate("78702-1234", &rates), found("787", "0.0825"));
+        assert_eq!(rate("75001", &rates), found("7", "0.05"));
+        assert_eq!(rate(" 78701\n", &rates), found("78701", "0.09"));
+    }
+
+    #[test]
+    fn without_a_prefix_the_state_then_anywhere_is_used() {
+        let rates = rates(&[("787", "0.0825"), ("TX", "0.0625"), ("*", "0.07")]);
+        assert_eq!(rate("75001", &rates), found("TX", "0.0625"));
+        assert_eq!(rate("10001", &rates), found("*", "0.07"));
+        assert_eq!(rate("", &rates), found("*", "0.07"));
+    }
+
+    #[test]
+    fn a_zip_code_no_configured_rate_covers_has_none() {
+        let rates = rates(&[("TX", "0.0625")]);
+        assert_eq!(rate("10001", &rates), None);
+        assert_eq!(rate("78701", &BTreeMap::new()), None);
+    }
+
+    #[test]
+    fn zip_codes_are_placed_in_their_state_by_prefix() {
+        assert_eq!(state_of("78701"), Some("TX"));
+        assert_eq!(state_of("10001"), Some("NY"));
+        assert_eq!(state_of("90210-1234"), Some("CA"));
+        assert_eq!(state_of("00000"), None);
+        assert_eq!(state_of("78"), None);
+        assert_eq!(state_of("abcde"), None);
+    }
+
+    #[test]
+    fn excluded_categories_are_not_estimated_with_a_configured_rate() {
+        let request = |category| RateRequest::new("78701", None, 9001, category);
+        assert_eq!(configured_category(&request(Some("general"))), Some(Some("general".to_owned())));
+        for category in EXCLUDED_CATEGORIES.iter() {
+            assert_eq!(configured_category(&request(Some(category))), None);
+        }
+    }
+
+    #[test]
+    fn a_product_never_looked_up_is_not_estimated_with_a_configured_rate() {
+        assert_eq!(configured_category(&RateRequest::new("78701", None, 9002, None)), None);
+    }
+}
@@ -1,154 +1,329 @@
 #[macro_use]
 extern crate lazy_static;
 
-use std::net::SocketAddr;
+mod balancer;
+mod cache;
+mod config;
+mod fallback;
+mod metrics;
+mod money;
+mod order;
+mod orders;
+mod problem;
+mod request_log;
+mod resilience;
+mod upstream;
+mod validate;
+
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::str;
+use std::time::Instant;
+use futures::{StreamExt, TryStreamExt};
+use hyper::body::HttpBody;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Method, Request, Response, StatusCode, Server};
-use serde::{Deserialize, Serialize};
-
-lazy_static! {
-    static ref SALES_TAX_RATE_SERVICE: String = {
-        if let Ok(url) = std::env::var("SALES_TAX_RATE_SERVICE") {
-            url
-        } else {
-            "http://localhost:8001/find_rate".into()
+use hyper::header::{HeaderValue, CONTENT_TYPE, ORIGIN};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use service_common::logging;
+use service_common::problem::{correlation_id, CORRELATION_ID, REQUEST_ID};
+use service_common::shutdown::{self, SHUTDOWN};
+use service_common::trace::{self, Span, SpanKind, TraceContext};
+use config::CONFIG;
+use metrics::METRICS;
+use orders::RateUsed;
+use problem::{ErrorCode, Problem};
+use request_log::RequestLog;
+use upstream::RateRequest;
+
+/// This is our service handler. It receives a Request, and answers it with
+/// CORS headers and its correlation ID whether it succeeds or fails, then
+/// logs it and records its span.
+async fn serve(req: Request<Body>, client: reqwest::Client) -> Result<Response<Body>, Infallible> {
+    let _in_flight = SHUTDOWN.begin();
+    let started = Instant::now();
+    let method = req.method().clone();
+    let route = metrics::route(req.uri().path());
+    let mut span = Span::start(format!("{} {}", method, route), SpanKind::Server, TraceContext::from_headers(req.headers()).as_ref());
+    span.set("http.request.method", method.as_str());
+    span.set("http.route", route);
+    let request_log = RequestLog::new(correlation_id(&req), span.context().clone());
+    span.set("correlation_id", request_log.correlation_id.as_str());
+    let origin = req.headers().get(ORIGIN).cloned();
+    let mut error = None;
+    let mut res = match handle_request(req, &client, &request_log).await {
+        Ok(res) => res,
+        Err(problem) => {
+            error = Some(problem.code);
+            if !problem.errors.is_empty() {
+                logging::debug("order rejected", serde_json::json!({
+                    "correlation_id": request_log.correlation_id,
+                    "errors": problem.errors,
+                }));
+            }
+            problem.response(&request_log.correlation_id)
         }
     };
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct Order {
-    order_id: i32,
-    product_id: i32,
-    quantity: i32,
-    subtotal: f32,
-    shipping_address: String,
-    shipping_zip: String,
-    total: f32,
-}
-
-/*
-impl Order {
-    fn new(
-        order_id: i32,
-        product_id: i32,
-        quantity: i32,
-        subtotal: f32,
-        shipping_address: String,
-        shipping_zip: String,
-        total: f32,
-    ) -> Self {
-        Self {
-            order_id,
-            product_id,
-            quantity,
-            subtotal,
-            shipping_address,
-            shipping_zip,
-            total,
+    let elapsed = started.elapsed();
+    let status = res.status().as_u16();
+    METRICS.record_request(method.as_str(), route, status, elapsed);
+    let mut fields = serde_json::json!({
+        "correlation_id": request_log.correlation_id,
+        "trace_id": request_log.trace.trace_id(),
+        "method": method.as_str(),
+        "route": route,
+        "status": status,
+        "latency_ms": logging::millis(elapsed),
+        "upstream_calls": request_log.upstream_calls(),
+        "upstream_ms": logging::millis(request_log.upstream_time()),
+    });
+    span.set("http.response.status_code", status);
+    if let Some(code) = error {
+        let code = serde_json::json!(code);
+        if status >= 500 {
+            span.fail(code.as_str().unwrap_or_default());
         }
+        span.set("error.type", code.clone());
+        fields["error"] = code;
+    }
+    logging::info("request", fields);
+    span.end();
+    let headers = res.headers_mut();
+    CONFIG.cors.add_headers(origin.as_ref(), headers);
+    if let Ok(value) = HeaderValue::from_str(&request_log.correlation_id) {
+        headers.insert(CORRELATION_ID, value.clone());
+        headers.insert(REQUEST_ID, value);
     }
+    Ok(res)
 }
-*/
 
-/// This is our service handler. It receives a Request, routes on its
-/// path, and returns a Future of a Response.
-async fn handle_request(req: Request<Body>) -> Result<Response<Body>, anyhow::Error> {
+/// Routes a request on its path, and returns a Future of a Response.
+async fn handle_request(req: Request<Body>, client: &reqwest::Client, request_log: &RequestLog) -> Result<Response<Body>, Problem> {
+    // Admin routes need the admin token, and are off without one. A browser
+    // cannot send it cross-origin, as `Authorization` is not an allowed header.
+    if req.uri().path().starts_with("/admin/") && req.method() != Method::OPTIONS && !CONFIG.admin.authorizes(req.headers()) {
+        return Err(Problem::unauthorized());
+    }
     match (req.method(), req.uri().path()) {
-        // CORS OPTIONS
-        (&Method::OPTIONS, "/compute") => Ok(response_build(&String::from(""))),
+        // CORS preflight
+        (&Method::OPTIONS, _) => Ok(Response::new(Body::empty())),
 
         // Serve some instructions at /
         (&Method::GET, "/") => Ok(Response::new(Body::from(
             "Try POSTing data to /compute such as: `curl localhost:8002/compute -XPOST -d '...'`",
         ))),
 
-        (&Method::POST, "/compute") => {
-            let byte_stream = hyper::body::to_bytes(req).await?;
-            let mut order: Order = serde_json::from_slice(&byte_stream).unwrap();
-
-            let client = reqwest::Client::new();
-
-            let sent_request = client.post(&*SALES_TAX_RATE_SERVICE)
-                .body(order.shipping_zip.clone())
-                .send()
-                .await;
-
-            let body = match sent_request {
-                Ok(response) => response.text().await,
-                Err(e) => {
-                    dbg!(e);
-                    let err_msg = r#"{"status":"error", "message":"Cannot connect to sales tax rate service"}"#;
-                    let mut res = Response::default();
-                    *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                    *res.body_mut() = Body::from(err_msg);
-                    return Ok(res);
-                },
-            };
+        // Liveness: the process is up and answering
+        (&Method::GET, "/healthz") => Ok(json_response(&serde_json::json!({"status": "ok"}))?),
 
-            let body_text = match body {
-                Ok(text) => text,
-                Err(e) => {
-                    dbg!(e);
-                    let err_msg = r#"{"status":"error", "message":"Cannot read response from sales tax rate service"}"#;
-                    let mut res = Response::default();
-                    *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                    *res.body_mut() = Body::from(err_msg);
-                    return Ok(res);
-                },
+        // Readiness: orders can be computed once a sales tax rate service
+        // replica answers. The periodic health checks are reported; only a
+        // replica not checked yet is asked now, briefly.
+        (&Method::GET, "/readyz") => {
+            let endpoints = match balancer::BALANCER.health() {
+                Some(endpoints) => endpoints,
+                None => balancer::BALANCER.check_health(client, balancer::COLD_START_TIMEOUT).await,
             };
-
-            let rate = match body_text.parse::<f32>() {
-                Ok(rate) => rate,
-                Err(e) => {
-                    dbg!(e);
-                    let err_msg = r#"{"status":"error", "message":"The zip code in the order does not have a corresponding sales tax rate."}"#;
-                    let mut bad_request = Response::default();
-                    *bad_request.status_mut() = StatusCode::BAD_REQUEST;
-                    *bad_request.body_mut() = Body::from(err_msg);
-                    return Ok(bad_request);
+            let ready = endpoints.iter().any(|endpoint| endpoint.up);
+            // A service shutting down takes no new work, whatever its checks say
+            let shutting_down = SHUTDOWN.is_requested();
+            let mut res = json_response(&serde_json::json!({
+                "status": if shutting_down { "shutting_down" } else if ready { "ready" } else { "not_ready" },
+                "checks": {
+                    "sales_tax_rate": {
+                        "status": if ready { "up" } else { "down" },
+                        "endpoints": endpoints,
+                    },
                 },
-            };
+            }))?;
+            if shutting_down || !ready {
+                *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            }
+            Ok(res)
+        }
 
-            order.total = order.subtotal * (1.0 + rate);
+        // Prometheus metrics
+        (&Method::GET, "/metrics") => {
+            let mut res = Response::new(Body::from(METRICS.render()));
+            res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4"));
+            Ok(res)
+        }
+
+        (&Method::POST, "/compute") => {
+            let byte_stream = read_body(req.into_body(), CONFIG.compute.max_body_bytes).await?;
+            let mut order = validate::parse_order(&byte_stream)?;
+            order.prepare_lines();
+
+            // Lines for the same product and category share one lookup, and
+            // the lookups run side by side
+            let mut keys = Vec::new();
+            for line in &order.line_items {
+                let key = (line.product_id, line.category.clone());
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+            let (zip, date) = (&order.shipping_zip, order.order_date);
+            let lookups: HashMap<_, _> = futures::stream::iter(keys)
+                .map(|key| async move {
+                    let request = RateRequest::new(zip, date, key.0, key.1.as_deref());
+                    let lookup = match cache::CACHE.fetch_rate(client, &request, request_log).await {
+                        Ok(lookup) => (lookup, None),
+                        Err(failure) => match fallback::estimate(&request, &failure) {
+                            Some(estimate) => (estimate.lookup, Some(estimate.reason)),
+                            None => return Err(failure),
+                        },
+                    };
+                    Ok((key, lookup))
+                })
+                .buffer_unordered(CONFIG.compute.max_concurrent_lookups)
+                .try_collect()
+                .await?;
+            for line in &mut order.line_items {
+                let (lookup, estimate_reason) = &lookups[&(line.product_id, line.category.clone())];
+                line.apply_rate(lookup, &order.currency);
+                if let Some(reason) = estimate_reason {
+                    line.tax_estimated = true;
+                    order.tax_estimated = true;
+                    order.tax_estimate_reason.get_or_insert_with(|| reason.clone());
+                }
+            }
+            order.summarize();
+            logging::debug("order computed", serde_json::json!({
+                "correlation_id": request_log.correlation_id,
+                "order": order,
+            }));
+
+            // An order that cannot be kept is not answered, so that every
+            // total a caller was given is on record
+            if let Some(store) = &*orders::STORE {
+                let mut rates: Vec<RateUsed> = lookups.iter()
+                    .map(|((product_id, _), (lookup, estimate_reason))| RateUsed::new(*product_id, lookup, estimate_reason.as_ref()))
+                    .collect();
+                rates.sort_by_key(|rate| rate.product_id);
+                store.record(&order, rates, &request_log.correlation_id)?;
+            }
             Ok(response_build(&serde_json::to_string_pretty(&order)?))
         }
 
+        // Computed orders, newest first, when they are kept
+        (&Method::GET, "/orders") => {
+            let query = orders::Query::parse(req.uri().query())?;
+            Ok(json_response(&serde_json::to_value(orders::store()?.list(&query)?)?)?)
+        }
+
+        // The latest computation of an order
+        (&Method::GET, path) if path.starts_with("/orders/") => {
+            let store = orders::store()?;
+            let order = path["/orders/".len()..].parse().ok()
+                .map_or(Ok(None), |order_id| store.get(order_id))?
+                .ok_or_else(|| Problem::new(ErrorCode::OrderNotFound, "No computed order is kept under this ID"))?;
+            Ok(json_response(&serde_json::to_value(order)?)?)
+        }
+
+        // How calls to the sales tax rate service are doing
+        (&Method::GET, "/status") => {
+            let status = serde_json::json!({
+                "sales_tax_rate": {
+                    "endpoints": balancer::BALANCER.status(),
+                    "balancing": &*balancer::BALANCER_SETTINGS,
+                    "breaker": resilience::BREAKER.status(),
+                    "settings": &*resilience::SETTINGS,
+                    "pool": &*upstream::POOL,
+                    "cache": {
+                        "stats": cache::CACHE.stats(),
+                        "settings": &*cache::SETTINGS,
+                    },
+                },
+            });
+            Ok(response_build(&serde_json::to_string_pretty(&status)?))
+        }
+
+        // Shut down gracefully, where there is no signal to do it with
+        (&Method::POST, "/admin/shutdown") => {
+            SHUTDOWN.request("admin");
+            let mut res = json_response(&serde_json::json!({
+                "status": "shutting_down",
+                "drain_ms": CONFIG.server.drain.as_millis() as u64,
+            }))?;
+            *res.status_mut() = StatusCode::ACCEPTED;
+            Ok(res)
+        }
+
         // Return the 404 Not Found for other routes.
-        _ => {
-            let mut not_found = Response::default();
-            *not_found.status_mut() = StatusCode::NOT_FOUND;
-            Ok(not_found)
+        _ => Err(Problem::not_found()),
+    }
+}
+
+/// Reads a request's body, or answers `body_too_large` once it is more than
+/// `limit` bytes, without reading the rest.
+async fn read_body(mut body: Body, limit: usize) -> Result<Vec<u8>, Problem> {
+    let too_large = || Problem::new(ErrorCode::BodyTooLarge, format!("The body must not be more than {} bytes", limit));
+    if body.size_hint().lower() > limit as u64 {
+        return Err(too_large());
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > limit {
+            return Err(too_large());
         }
+        bytes.extend_from_slice(&chunk);
     }
+    Ok(bytes)
 }
 
-// CORS headers
+// CORS headers are added to every response by `serve`
 fn response_build(body: &str) -> Response<Body> {
-    Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "GET, POST, OPTIONS")
-        .header("Access-Control-Allow-Headers", "api,Keep-Alive,User-Agent,Content-Type")
-        .body(Body::from(body.to_owned()))
-        .unwrap()
+    Response::new(Body::from(body.to_owned()))
+}
+
+fn json_response(body: &serde_json::Value) -> Result<Response<Body>, serde_json::Error> {
+    let mut res = response_build(&serde_json::to_string_pretty(body)?);
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Ok(res)
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8002));
-    let make_svc = make_service_fn(|_| {
+    // Fail at startup, not on the first order, if the configuration is invalid
+    lazy_static::initialize(&CONFIG);
+    logging::init("order_total", CONFIG.log.level);
+    logging::info("configuration", serde_json::json!({"config": service_common::config::redacted(&*CONFIG)}));
+    lazy_static::initialize(&orders::STORE);
+
+    trace::spawn_exporter(&CONFIG.tracing);
+
+    // One client for every order, so connections to the rate service are reused
+    let client = upstream::client()?;
+
+    lazy_static::initialize(&balancer::BALANCER);
+    let health_check = balancer::BALANCER_SETTINGS.health_check;
+    if !health_check.is_zero() {
+        let client = client.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(health_check);
+            loop {
+                interval.tick().await;
+                balancer::BALANCER.check_health(&client, resilience::SETTINGS.timeout).await;
+            }
+        });
+    }
+
+    let addr = CONFIG.server.addr();
+    let make_svc = make_service_fn(move |_| {
+        let client = client.clone();
         async move {
             Ok::<_, Infallible>(service_fn(move |req| {
-                handle_request(req)
+                serve(req, client.clone())
             }))
         }
     });
-    let server = Server::bind(&addr).serve(make_svc);
-    dbg!("Server started on port 8002");
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
-    }
+    let server = Server::bind(&addr).serve(make_svc).with_graceful_shutdown(SHUTDOWN.requested());
+    logging::info("server started", serde_json::json!({
+        "addr": addr.to_string(),
+        "sales_tax_rate_endpoints": balancer::BALANCER.count(),
+    }));
+
+    shutdown::run(server, CONFIG.server.drain).await;
     Ok(())
 }
@@ -0,0 +1,111 @@
+//! Metrics in the Prometheus text format, served at `/metrics`: requests
+//! and their latency by route and status, calls to the sales tax rate
+//! service, and the state of the cache, circuit breaker and replicas.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use service_common::metrics::{Exposition, Histogram, Requests};
+use crate::balancer::BALANCER;
+use crate::cache::CACHE;
+use crate::resilience::{BreakerState, BREAKER};
+
+/// The routes requests are counted under. Any other path is counted as
+/// `other`, so that stray paths cannot grow the metrics without bound.
+const ROUTES: &[&str] = &["/", "/compute", "/orders", "/status", "/healthz", "/readyz", "/metrics", "/admin/shutdown"];
+
+/// The route a request for `path` is counted and logged under.
+pub fn route(path: &str) -> &'static str {
+    if path.starts_with("/orders/") {
+        return "/orders/{order_id}";
+    }
+    ROUTES.iter().find(|route| **route == path).copied().unwrap_or("other")
+}
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    requests: Requests,
+    /// Calls to the sales tax rate service by replica and outcome.
+    upstream_calls: Mutex<BTreeMap<(String, &'static str), Histogram>>,
+    /// Lookups failed fast because the circuit breaker was open.
+    breaker_rejections: AtomicU64,
+}
+
+impl Metrics {
+    /// Records a request answered in `elapsed`.
+    pub fn record_request(&self, method: &str, route: &'static str, status: u16, elapsed: Duration) {
+        self.requests.record(method, route, status, elapsed);
+    }
+
+    /// Records a call to a replica of the sales tax rate service and its
+    /// outcome, `ok` or why it failed.
+    pub fn record_upstream_call(&self, endpoint: &str, outcome: &'static str, elapsed: Duration) {
+        self.upstream_calls.lock().unwrap()
+            .entry((endpoint.to_owned(), outcome))
+            .or_default()
+            .observe(elapsed);
+    }
+
+    pub fn record_breaker_rejection(&self) {
+        self.breaker_rejections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// All metrics, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = Exposition::default();
+
+        self.requests.render(&mut out, "order_total_http_request_duration_seconds");
+
+        let upstream_calls = self.upstream_calls.lock().unwrap();
+        out.header("order_total_upstream_request_duration_seconds", "histogram", "Calls to the sales tax rate service, by replica and outcome.");
+        for ((endpoint, outcome), histogram) in upstream_calls.iter() {
+            out.histogram("order_total_upstream_request_duration_seconds", &[("endpoint", endpoint), ("outcome", outcome)], histogram);
+        }
+        // A zip code with no rate, or a malformed one, is an answer rather than an error
+        out.header("order_total_upstream_errors_total", "counter", "Calls to the sales tax rate service that failed, by replica and reason.");
+        let errors = upstream_calls.iter().filter(|((_, outcome), _)| !matches!(*outcome, "ok" | "not_found" | "invalid_zip" | "invalid_category"));
+        for ((endpoint, outcome), histogram) in errors {
+            out.sample("order_total_upstream_errors_total", &[("endpoint", endpoint), ("reason", outcome)], histogram.count());
+        }
+        drop(upstream_calls);
+
+        out.header("order_total_upstream_endpoint_ejected", "gauge", "Whether a replica of the sales tax rate service is ejected.");
+        let endpoints = BALANCER.status();
+        for endpoint in &endpoints {
+            out.sample("order_total_upstream_endpoint_ejected", &[("endpoint", endpoint.url)], u8::from(endpoint.ejected));
+        }
+        out.header("order_total_upstream_endpoint_outstanding", "gauge", "Lookups in flight on a replica of the sales tax rate service.");
+        for endpoint in &endpoints {
+            out.sample("order_total_upstream_endpoint_outstanding", &[("endpoint", endpoint.url)], endpoint.outstanding);
+        }
+
+        let breaker = BREAKER.status();
+        out.header("order_total_circuit_breaker_state", "gauge", "The state of the circuit breaker guarding the sales tax rate service.");
+        for (state, name) in [(BreakerState::Closed, "closed"), (BreakerState::Open, "open"), (BreakerState::HalfOpen, "half_open")] {
+            out.sample("order_total_circuit_breaker_state", &[("state", name)], u8::from(breaker.state == state));
+        }
+        out.header("order_total_circuit_breaker_consecutive_failures", "gauge", "Failed calls to the sales tax rate service in a row.");
+        out.sample("order_total_circuit_breaker_consecutive_failures", &[], breaker.consecutive_failures);
+        out.header("order_total_circuit_breaker_rejections_total", "counter", "Lookups failed fast because the circuit breaker was open.");
+        out.sample("order_total_circuit_breaker_rejections_total", &[], self.breaker_rejections.load(Ordering::Relaxed));
+
+        let cache = CACHE.stats();
+        out.header("order_total_rate_cache_entries", "gauge", "Rate lookups in the cache.");
+        out.sample("order_total_rate_cache_entries", &[], cache.entries);
+        out.header("order_total_rate_cache_lookups_total", "counter", "Rate lookups through the cache, by how they were answered.");
+        for (result, count) in [("hit", cache.hits), ("negative_hit", cache.negative_hits), ("stale_hit", cache.stale_hits), ("miss", cache.misses)] {
+            out.sample("order_total_rate_cache_lookups_total", &[("result", result)], count);
+        }
+        out.header("order_total_rate_cache_failed_refreshes_total", "counter", "Background refreshes of stale rates that failed.");
+        out.sample("order_total_rate_cache_failed_refreshes_total", &[], cache.failed_refreshes);
+        out.header("order_total_rate_cache_evictions_total", "counter", "Entries dropped to keep the cache within its size.");
+        out.sample("order_total_rate_cache_evictions_total", &[], cache.evictions);
+
+        out.into_text()
+    }
+}
@@ -0,0 +1,97 @@
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use crate::config::CONFIG;
+
+/// How amounts are rounded to the currency's minor unit.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+    #[default]
+    HalfUp,
+    HalfEven,
+    Up,
+    Down,
+}
+
+impl Rounding {
+    fn strategy(self) -> RoundingStrategy {
+        match self {
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::Up => RoundingStrategy::AwayFromZero,
+            Rounding::Down => RoundingStrategy::ToZero,
+        }
+    }
+}
+
+pub fn default_currency() -> String {
+    "USD".into()
+}
+
+/// The number of decimal places in a currency's minor unit.
+pub fn minor_units(currency: &str) -> u32 {
+    match currency {
+        "JPY" | "KRW" | "CLP" | "ISK" | "VND" => 0,
+        "BHD" | "JOD" | "KWD" | "OMR" | "TND" => 3,
+        _ => 2,
+    }
+}
+
+/// Rounds an amount to the currency's minor unit, keeping exactly that many
+/// decimal places so that 20 dollars is written as `20.00`.
+pub fn round(amount: Decimal, currency: &str) -> Decimal {
+    round_with(amount, currency, CONFIG.tax.rounding)
+}
+
+fn round_with(amount: Decimal, currency: &str, rounding: Rounding) -> Decimal {
+    let places = minor_units(currency);
+    let mut rounded = amount.round_dp_with_strategy(places, rounding.strategy());
+    rounded.rescale(places);
+    rounded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use super::*;
+
+    fn rounded(amount: &str, currency: &str, rounding: Rounding) -> String {
+        round_with(Decimal::from_str(amount).unwrap(), currency, rounding).to_string()
+    }
+
+    #[test]
+    fn half_up_rounds_midpoints_away_from_zero() {
+        assert_eq!(rounded("1.005", "USD", Rounding::HalfUp), "1.01");
+        assert_eq!(rounded("1.015", "USD", Rounding::HalfUp), "1.02");
+        assert_eq!(rounded("1.004", "USD", Rounding::HalfUp), "1.00");
+        assert_eq!(rounded("-1.005", "USD", Rounding::HalfUp), "-1.01");
+    }
+
+    #[test]
+    fn half_even_rounds_midpoints_to_the_even_neighbour() {
+        assert_eq!(rounded("1.005", "USD", Rounding::HalfEven), "1.00");
+        assert_eq!(rounded("1.015", "USD", Rounding::HalfEven), "1.02");
+        assert_eq!(rounded("1.0051", "USD", Rounding::HalfEven), "1.01");
+    }
+
+    #[test]
+    fn up_rounds_any_fraction_away_from_zero() {
+        assert_eq!(rounded("1.001", "USD", Rounding::Up), "1.01");
+        assert_eq!(rounded("1.00", "USD", Rounding::Up), "1.00");
+        assert_eq!(rounded("-1.001", "USD", Rounding::Up), "-1.01");
+    }
+
+    #[test]
+    fn down_drops_any_fraction() {
+        assert_eq!(rounded("1.009", "USD", Rounding::Down), "1.00");
+        assert_eq!(rounded("-1.009", "USD", Rounding::Down), "-1.00");
+    }
+
+    #[test]
+    fn amounts_keep_exactly_the_decimal_places_of_the_currency() {
+        assert_eq!(rounded("20", "USD", Rounding::HalfUp), "20.00");
+        assert_eq!(rounded("1234.5", "JPY", Rounding::HalfUp), "1235");
+        assert_eq!(rounded("1.2345", "KWD", Rounding::HalfUp), "1.235");
+        assert_eq!(rounded("7", "KWD", Rounding::Down), "7.000");
+    }
+}
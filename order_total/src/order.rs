@@ -0,0 +1,173 @@
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use crate::money;
+use crate::upstream::{Jurisdiction, RateLookup};
+
+/// An order to compute the total for. Amounts are exact decimals in
+/// `currency`, and are written out as strings rounded to its minor unit.
+///
+/// An order is either a list of `line_items`, or the original single
+/// product shape of `product_id`, `quantity` and `subtotal`, which is
+/// computed as a one-line order.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Order {
+    pub order_id: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product_id: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quantity: Option<i32>,
+    #[serde(default)]
+    pub line_items: Vec<LineItem>,
+    #[serde(default)]
+    pub subtotal: Decimal,
+    #[serde(default = "money::default_currency")]
+    pub currency: String,
+    pub shipping_address: String,
+    pub shipping_zip: String,
+    /// The date the order was placed, so a recompute uses the rates that
+    /// applied then. Orders without one are taxed at today's rates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order_date: Option<NaiveDate>,
+    /// The taxability category of a single product order. When left out the
+    /// rate service uses the product's category on file, and the response
+    /// reports the category that was applied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(default, skip_deserializing)]
+    pub tax: Decimal,
+    #[serde(default)]
+    pub total: Decimal,
+    #[serde(default, skip_deserializing)]
+    pub tax_breakdown: Vec<JurisdictionTotal>,
+    /// Whether any line's tax was estimated because the sales tax rate
+    /// service could not be asked, and why.
+    #[serde(default, skip_deserializing, skip_serializing_if = "std::ops::Not::not")]
+    pub tax_estimated: bool,
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub tax_estimate_reason: Option<String>,
+}
+
+/// One product in an order. Its subtotal is `quantity` times `unit_price`
+/// less `discount`, and it is taxed under its own category.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LineItem {
+    pub product_id: i32,
+    pub quantity: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit_price: Option<Decimal>,
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    pub discount: Decimal,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(default, skip_deserializing)]
+    pub subtotal: Decimal,
+    #[serde(default, skip_deserializing)]
+    pub tax: Decimal,
+    #[serde(default, skip_deserializing)]
+    pub total: Decimal,
+    #[serde(default, skip_deserializing)]
+    pub tax_breakdown: Vec<JurisdictionTax>,
+    #[serde(default, skip_deserializing, skip_serializing_if = "std::ops::Not::not")]
+    pub tax_estimated: bool,
+}
+
+/// The tax collected on a line for one jurisdiction.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JurisdictionTax {
+    #[serde(flatten)]
+    pub jurisdiction: Jurisdiction,
+    pub amount: Decimal,
+}
+
+/// The tax collected on the whole order for one jurisdiction.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JurisdictionTotal {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: String,
+    pub code: String,
+    pub amount: Decimal,
+}
+
+impl Order {
+    /// Turns a single product order into a one-line order and works out
+    /// each line's subtotal. The order must have been validated, so that it
+    /// has either lines with a unit price or a product.
+    pub fn prepare_lines(&mut self) {
+        if self.line_items.is_empty() {
+            let product_id = self.product_id.unwrap_or_default();
+            self.line_items.push(LineItem {
+                product_id,
+                quantity: self.quantity.unwrap_or(1),
+                unit_price: None,
+                discount: Decimal::ZERO,
+                category: self.category.clone(),
+                subtotal: self.subtotal,
+                tax: Decimal::ZERO,
+                total: Decimal::ZERO,
+                tax_breakdown: Vec::new(),
+                tax_estimated: false,
+            });
+        } else {
+            for line in &mut self.line_items {
+                let unit_price = line.unit_price.unwrap_or_default();
+                line.subtotal = money::round(unit_price * Decimal::from(line.quantity), &self.currency)
+                    - money::round(line.discount, &self.currency);
+            }
+        }
+        for line in &mut self.line_items {
+            line.subtotal = money::round(line.subtotal, &self.currency);
+        }
+    }
+
+    /// Adds up the lines into the order's subtotal, tax, total and tax per
+    /// jurisdiction.
+    pub fn summarize(&mut self) {
+        self.subtotal = self.line_items.iter().map(|line| line.subtotal).sum();
+        self.tax = self.line_items.iter().map(|line| line.tax).sum();
+        self.total = self.subtotal + self.tax;
+
+        self.tax_breakdown.clear();
+        for tax in self.line_items.iter().flat_map(|line| &line.tax_breakdown) {
+            let jurisdiction = &tax.jurisdiction;
+            match self.tax_breakdown.iter_mut().find(|total| total.code == jurisdiction.code && total.kind == jurisdiction.kind) {
+                Some(total) => total.amount += tax.amount,
+                None => self.tax_breakdown.push(JurisdictionTotal {
+                    kind: jurisdiction.kind.clone(),
+                    name: jurisdiction.name.clone(),
+                    code: jurisdiction.code.clone(),
+                    amount: tax.amount,
+                }),
+            }
+        }
+
+        // A single product order reports the category it was taxed under
+        if self.product_id.is_some() && self.line_items.len() == 1 {
+            self.category = self.line_items[0].category.clone();
+        }
+    }
+}
+
+impl LineItem {
+    /// Taxes the line at the rate looked up for it. Each jurisdiction's tax
+    /// is rounded on its own, and the line's tax is their sum so that the
+    /// breakdown always adds up.
+    pub fn apply_rate(&mut self, lookup: &RateLookup, currency: &str) {
+        if lookup.category.is_some() {
+            self.category = lookup.category.clone();
+        }
+        self.tax_breakdown = lookup.jurisdictions.iter()
+            .map(|jurisdiction| JurisdictionTax {
+                amount: money::round(self.subtotal * jurisdiction.rate, currency),
+                jurisdiction: jurisdiction.clone(),
+            })
+            .collect();
+        self.tax = if self.tax_breakdown.is_empty() {
+            money::round(self.subtotal * lookup.rate, currency)
+        } else {
+            self.tax_breakdown.iter().map(|tax| tax.amount).sum()
+        };
+        self.total = self.subtotal + self.tax;
+    }
+}
@@ -0,0 +1,439 @@
+//! A record of computed orders, so that support and finance can look up
+//! what was charged. When `orders.file` is set, every order `/compute`
+//! answers is appended to it as a JSON line: its inputs and totals, the
+//! rate each line was taxed at and the rate table it came from, and when
+//! it was computed. Personal data such as the shipping address is left
+//! out. At startup the file is indexed, and orders are read back from it
+//! as they are asked for.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use anyhow::Context;
+use chrono::{DateTime, NaiveDate, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use service_common::logging;
+use crate::config::CONFIG;
+use crate::order::Order;
+use crate::problem::{ErrorCode, Problem};
+use crate::upstream::RateLookup;
+
+/// Where computed orders are kept.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct OrderStoreSettings {
+    /// The file orders are appended to. Without one, orders are not kept.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// The most orders one page of `GET /orders` may hold.
+    pub max_page_size: usize,
+}
+
+impl Default for OrderStoreSettings {
+    fn default() -> Self {
+        OrderStoreSettings { file: None, max_page_size: 100 }
+    }
+}
+
+/// A page of `GET /orders` holds this many orders unless `limit` says
+/// otherwise.
+const DEFAULT_PAGE_SIZE: usize = 20;
+
+lazy_static! {
+    /// The store, if orders are kept. It is opened at startup, so that a
+    /// file that cannot be read stops the service.
+    pub static ref STORE: Option<OrderStore> = OrderStore::open_configured()
+        .unwrap_or_else(|e| panic!("Cannot open the order store: {:#}", e));
+}
+
+/// The store, or a problem saying orders are not kept.
+pub fn store() -> Result<&'static OrderStore, Problem> {
+    STORE.as_ref().ok_or_else(|| Problem::new(ErrorCode::NotFound, "Computed orders are not kept"))
+}
+
+/// One computation of an order, as stored.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoredOrder {
+    pub order_id: i32,
+    pub computed_at: DateTime<Utc>,
+    /// The correlation ID of the `/compute` request, to find it in the logs.
+    pub correlation_id: String,
+    pub shipping_zip: String,
+    pub currency: String,
+    pub tax: Decimal,
+    pub total: Decimal,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tax_estimated: bool,
+    /// The rate each product was taxed at.
+    pub rates: Vec<RateUsed>,
+    /// The order as `/compute` answered it, without personal data: its
+    /// inputs, and the tax and totals charged.
+    pub order: serde_json::Value,
+}
+
+/// The rate a product in an order was taxed at, and where it came from.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RateUsed {
+    pub product_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(with = "rust_decimal::serde::float")]
+    pub rate: Decimal,
+    /// The version of the sales tax rate table the rate came from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub table_version: Option<String>,
+    /// Why the rate was estimated, if the rate service could not be asked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimate_reason: Option<String>,
+}
+
+impl RateUsed {
+    pub fn new(product_id: i32, lookup: &RateLookup, estimate_reason: Option<&String>) -> Self {
+        RateUsed {
+            product_id,
+            category: lookup.category.clone(),
+            rate: lookup.rate,
+            table_version: lookup.table_version.clone(),
+            estimate_reason: estimate_reason.cloned(),
+        }
+    }
+}
+
+/// Which stored orders `GET /orders` lists, and which page of them.
+#[derive(Default, Debug)]
+pub struct Query {
+    order_id: Option<i32>,
+    zip: Option<String>,
+    /// The first day, in UTC, orders were computed on.
+    from: Option<NaiveDate>,
+    /// The last day, in UTC, orders were computed on.
+    to: Option<NaiveDate>,
+    /// Orders stored before this position, from the previous page.
+    cursor: Option<usize>,
+    limit: usize,
+}
+
+impl Query {
+    /// Reads a query from `GET /orders`'s query string.
+    pub fn parse(query: Option<&str>) -> Result<Self, Problem> {
+        let invalid = |name: &str, value: &str, expected: &str| {
+            Problem::new(ErrorCode::InvalidQuery, format!("{} must be {}, not {:?}", name, expected, value))
+        };
+        let mut parsed = Query { limit: DEFAULT_PAGE_SIZE.min(CONFIG.orders.max_page_size), ..Query::default() };
+        let pairs = query.unwrap_or_default().split('&').filter(|pair| !pair.is_empty());
+        for pair in pairs {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match name {
+                "order_id" => parsed.order_id = Some(value.parse().map_err(|_| invalid(name, value, "a whole number"))?),
+                "zip" => parsed.zip = Some(value.to_owned()),
+                "from" => parsed.from = Some(value.parse().map_err(|_| invalid(name, value, "a YYYY-MM-DD date"))?),
+                "to" => parsed.to = Some(value.parse().map_err(|_| invalid(name, value, "a YYYY-MM-DD date"))?),
+                "cursor" => parsed.cursor = Some(value.parse().map_err(|_| invalid(name, value, "a cursor from a previous page"))?),
+                "limit" => {
+                    let max = CONFIG.orders.max_page_size;
+                    parsed.limit = value.parse().ok()
+                        .filter(|limit| (1..=max).contains(limit))
+                        .ok_or_else(|| invalid(name, value, &format!("between 1 and {}", max)))?;
+                }
+                _ => return Err(Problem::new(ErrorCode::InvalidQuery, format!("Unknown parameter {:?}", name))),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        self.order_id.iter().all(|order_id| entry.order_id == *order_id)
+            && self.zip.iter().all(|zip| entry.shipping_zip.starts_with(zip.as_str()))
+            && self.from.iter().all(|from| entry.computed_on >= *from)
+            && self.to.iter().all(|to| entry.computed_on <= *to)
+    }
+}
+
+/// A page of stored orders, newest first.
+#[derive(Serialize, Debug)]
+pub struct Page {
+    pub orders: Vec<StoredOrder>,
+    /// Passed as `cursor` to get the next page, if there are more orders.
+    pub next_cursor: Option<String>,
+}
+
+pub struct OrderStore {
+    /// Where each stored order is in the file, oldest first.
+    index: Mutex<Index>,
+    /// The file, read at the offsets in the index and appended to.
+    file: Mutex<File>,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: Vec<Entry>,
+    /// The position in `entries` of each order's latest computation.
+    latest: HashMap<i32, usize>,
+}
+
+/// Where a stored order's line starts in the file, and what `GET /orders`
+/// filters on, so that only the orders served are read back.
+struct Entry {
+    offset: u64,
+    order_id: i32,
+    computed_on: NaiveDate,
+    shipping_zip: String,
+}
+
+/// The fields of a stored order that its entry is made from.
+#[derive(Deserialize)]
+struct Summary {
+    order_id: i32,
+    computed_at: DateTime<Utc>,
+    shipping_zip: String,
+}
+
+impl Index {
+    fn push(&mut self, offset: u64, summary: Summary) {
+        self.latest.insert(summary.order_id, self.entries.len());
+        self.entries.push(Entry {
+            offset,
+            order_id: summary.order_id,
+            computed_on: summary.computed_at.date_naive(),
+            shipping_zip: summary.shipping_zip,
+        });
+    }
+
+    /// Where the orders on the page a query asks for are, newest first, and
+    /// the cursor of the next page, if there are more orders.
+    fn page(&self, query: &Query) -> (Vec<u64>, Option<String>) {
+        let end = query.cursor.unwrap_or(self.entries.len()).min(self.entries.len());
+        let mut matching = self.entries[..end].iter().enumerate().rev().filter(|(_, entry)| query.matches(entry));
+        let page: Vec<(usize, &Entry)> = matching.by_ref().take(query.limit).collect();
+        let next_cursor = match page.last() {
+            Some((position, _)) if matching.next().is_some() => Some(position.to_string()),
+            _ => None,
+        };
+        (page.iter().map(|(_, entry)| entry.offset).collect(), next_cursor)
+    }
+}
+
+impl OrderStore {
+    /// Opens the configured store, if orders are kept.
+    fn open_configured() -> anyhow::Result<Option<OrderStore>> {
+        let Some(path) = &CONFIG.orders.file else {
+            return Ok(None);
+        };
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)
+            .with_context(|| format!("cannot open {}", path.display()))?;
+        let (index, ends_with_newline) = read_index(&mut file).with_context(|| format!("cannot read {}", path.display()))?;
+        // Finish a line cut short, so the next order starts a line of its own
+        if !ends_with_newline {
+            writeln!(file).with_context(|| format!("cannot write {}", path.display()))?;
+        }
+        logging::info("order store opened", serde_json::json!({"file": path, "orders": index.entries.len()}));
+        Ok(Some(OrderStore { index: Mutex::new(index), file: Mutex::new(file) }))
+    }
+
+    /// Stores a computed order. It is written to the file before it can be
+    /// looked up, so what is served is always on disk. Personal data, such
+    /// as the shipping address, is left out.
+    pub fn record(&self, order: &Order, rates: Vec<RateUsed>, correlation_id: &str) -> anyhow::Result<()> {
+        let stored = StoredOrder {
+            order_id: order.order_id,
+            computed_at: DateTime::<Utc>::from(SystemTime::now()),
+            correlation_id: correlation_id.to_owned(),
+            shipping_zip: order.shipping_zip.clone(),
+            currency: order.currency.clone(),
+            tax: order.tax,
+            total: order.total,
+            tax_estimated: order.tax_estimated,
+            rates,
+            order: without_personal_data(serde_json::to_value(order)?),
+        };
+        let line = serde_json::to_string(&stored)?;
+        let mut file = self.file.lock().unwrap();
+        let offset = file.seek(SeekFrom::End(0))?;
+        writeln!(file, "{}", line)?;
+        file.flush()?;
+        let summary = Summary { order_id: stored.order_id, computed_at: stored.computed_at, shipping_zip: stored.shipping_zip };
+        self.index.lock().unwrap().push(offset, summary);
+        Ok(())
+    }
+
+    /// The latest computation of an order.
+    pub fn get(&self, order_id: i32) -> anyhow::Result<Option<StoredOrder>> {
+        let offset = {
+            let index = self.index.lock().unwrap();
+            match index.latest.get(&order_id) {
+                Some(position) => index.entries[*position].offset,
+                None => return Ok(None),
+            }
+        };
+        self.read_at(offset).map(Some)
+    }
+
+    /// The page of orders a query asks for, newest first.
+    pub fn list(&self, query: &Query) -> anyhow::Result<Page> {
+        let (offsets, next_cursor) = self.index.lock().unwrap().page(query);
+        let orders = offsets.into_iter().map(|offset| self.read_at(offset)).collect::<anyhow::Result<_>>()?;
+        Ok(Page { orders, next_cursor })
+    }
+
+    /// Reads back the order stored at `offset`.
+    fn read_at(&self, offset: u64) -> anyhow::Result<StoredOrder> {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut line = String::new();
+        BufReader::new(&mut *file).read_line(&mut line)?;
+        let mut order: StoredOrder = serde_json::from_str(&line)
+            .with_context(|| format!("the order stored at offset {} cannot be read", offset))?;
+        // Orders stored before personal data was left out still hold it
+        order.order = without_personal_data(order.order);
+        Ok(order)
+    }
+}
+
+/// Indexes the stored orders in the file, and says whether it ends with a
+/// complete line. A line that cannot be read, such as one cut short when
+/// the service was stopped while writing it, is skipped.
+fn read_index(file: &mut File) -> std::io::Result<(Index, bool)> {
+    let mut index = Index::default();
+    let mut unreadable = BTreeMap::new();
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(0))?;
+    let mut offset = 0;
+    let mut number = 0;
+    let mut line = String::new();
+    let mut ends_with_newline = true;
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 {
+            break;
+        }
+        number += 1;
+        ends_with_newline = line.ends_with('\n');
+        if !line.trim().is_empty() {
+            match serde_json::from_str::<Summary>(&line) {
+                Ok(summary) => index.push(offset, summary),
+                Err(e) => {
+                    unreadable.insert(number, e.to_string());
+                }
+            }
+        }
+        offset += read as u64;
+    }
+    if !unreadable.is_empty() {
+        logging::warn("skipped unreadable stored orders", serde_json::json!({"lines": unreadable}));
+    }
+    Ok((index, ends_with_newline))
+}
+
+/// An order as JSON, without the fields that may hold personal data.
+fn without_personal_data(mut order: serde_json::Value) -> serde_json::Value {
+    if let Some(fields) = order.as_object_mut() {
+        fields.retain(|name, _| !logging::PII_FIELDS.contains(&name.as_str()));
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(text: &str) -> Query {
+        Query::parse(Some(text)).unwrap()
+    }
+
+    fn rejected(text: &str) -> String {
+        Query::parse(Some(text)).unwrap_err().detail
+    }
+
+    /// An index of orders 1 to `count`, one a day from 2024-01-01, with
+    /// each order at the offset of its ID.
+    fn index(count: i32) -> Index {
+        let mut index = Index::default();
+        for order_id in 1..=count {
+            let computed_at = format!("2024-01-{:02}T12:00:00Z", order_id).parse().unwrap();
+            let shipping_zip = if order_id % 2 == 0 { "78701" } else { "10001" }.to_owned();
+            index.push(order_id as u64, Summary { order_id, computed_at, shipping_zip });
+        }
+        index
+    }
+
+    /// Follows the cursors through every page of a query.
+    fn pages(index: &Index, text: &str) -> Vec<Vec<u64>> {
+        let mut pages = Vec::new();
+        let mut cursor = String::new();
+        loop {
+            let (offsets, next_cursor) = index.page(&query(&format!("{}{}", text, cursor)));
+            pages.push(offsets);
+            match next_cursor {
+                Some(next_cursor) => cursor = format!("&cursor={}", next_cursor),
+                None => return pages,
+            }
+        }
+    }
+
+    #[test]
+    fn a_query_with_no_parameters_lists_a_default_page_of_everything() {
+        for text in [None, Some("")] {
+            let query = Query::parse(text).unwrap();
+            assert_eq!(query.limit, DEFAULT_PAGE_SIZE.min(CONFIG.orders.max_page_size));
+            assert!(query.order_id.is_none() && query.zip.is_none() && query.cursor.is_none());
+        }
+    }
+
+    #[test]
+    fn every_parameter_is_read() {
+        let query = query("order_id=7&zip=787&from=2024-01-02&to=2024-01-31&cursor=12&limit=5");
+        assert_eq!(query.order_id, Some(7));
+        assert_eq!(query.zip.as_deref(), Some("787"));
+        assert_eq!(query.from, NaiveDate::from_ymd_opt(2024, 1, 2));
+        assert_eq!(query.to, NaiveDate::from_ymd_opt(2024, 1, 31));
+        assert_eq!(query.cursor, Some(12));
+        assert_eq!(query.limit, 5);
+    }
+
+    #[test]
+    fn unreadable_parameters_are_rejected() {
+        assert!(rejected("order_id=seven").contains("order_id must be a whole number"));
+        assert!(rejected("from=01/02/2024").contains("from must be a YYYY-MM-DD date"));
+        assert!(rejected("cursor=-1").contains("a cursor from a previous page"));
+        assert!(rejected("limit=0").contains("limit must be between 1 and"));
+        let too_many = format!("limit={}", CONFIG.orders.max_page_size + 1);
+        assert!(rejected(&too_many).contains("limit must be between 1 and"));
+        assert!(rejected("page=2").contains("Unknown parameter \"page\""));
+    }
+
+    #[test]
+    fn pages_list_the_newest_orders_first() {
+        let index = index(5);
+        assert_eq!(pages(&index, "limit=2"), [vec![5, 4], vec![3, 2], vec![1]]);
+        assert_eq!(pages(&index, "limit=5"), [vec![5, 4, 3, 2, 1]]);
+    }
+
+    #[test]
+    fn a_full_last_page_has_no_next_cursor() {
+        assert_eq!(pages(&index(4), "limit=2"), [vec![4, 3], vec![2, 1]]);
+        assert_eq!(index(0).page(&query("")), (Vec::new(), None));
+    }
+
+    #[test]
+    fn filters_apply_across_pages() {
+        let index = index(7);
+        assert_eq!(pages(&index, "zip=787&limit=2"), [vec![6, 4], vec![2]]);
+        assert_eq!(pages(&index, "from=2024-01-02&to=2024-01-05&limit=3"), [vec![5, 4, 3], vec![2]]);
+        assert_eq!(pages(&index, "order_id=3"), [vec![3]]);
+    }
+
+    #[test]
+    fn orders_stored_after_the_first_page_do_not_shift_later_pages() {
+        let mut index = index(3);
+        let (_, cursor) = index.page(&query("limit=2"));
+        let computed_at = "2024-01-04T12:00:00Z".parse().unwrap();
+        index.push(4, Summary { order_id: 4, computed_at, shipping_zip: "78701".into() });
+        let (offsets, _) = index.page(&query(&format!("limit=2&cursor={}", cursor.unwrap())));
+        assert_eq!(offsets, [1]);
+    }
+}
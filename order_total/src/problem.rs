@@ -0,0 +1,124 @@
+//! The ways a request to `order_total` can fail. Each is answered as an
+//! RFC 7807 problem with a stable `code`, and an invalid order's problem
+//! lists every field that was rejected.
+
+use std::fmt::Display;
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+use service_common::{logging, problem};
+use crate::validate::Violation;
+
+/// The stable, machine-readable reason a request failed.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The body is not JSON.
+    MalformedJson,
+    /// The body is larger than `/compute` reads.
+    BodyTooLarge,
+    /// The order is JSON, but not a valid order.
+    InvalidOrder,
+    /// The order's zip code has no sales tax rate on file.
+    RateNotFound,
+    /// The sales tax rate service could not be reached.
+    RateServiceUnavailable,
+    /// The sales tax rate service did not answer in time.
+    RateServiceTimeout,
+    /// The sales tax rate service has been failing, and is not being called
+    /// until the circuit breaker lets a trial call through.
+    CircuitOpen,
+    /// The sales tax rate service failed or answered with nonsense.
+    RateServiceError,
+    /// A query string parameter is malformed or out of range.
+    InvalidQuery,
+    /// No computed order is stored under the ID.
+    OrderNotFound,
+    /// An admin route was called without the admin token, or admin routes
+    /// are off.
+    Unauthorized,
+    /// No such route.
+    NotFound,
+    /// Anything else that went wrong on our side.
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::MalformedJson | ErrorCode::InvalidQuery => StatusCode::BAD_REQUEST,
+            ErrorCode::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::InvalidOrder | ErrorCode::RateNotFound => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::RateServiceUnavailable | ErrorCode::RateServiceError => StatusCode::BAD_GATEWAY,
+            ErrorCode::RateServiceTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::CircuitOpen => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::OrderNotFound | ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// A failed request, answered as a problem.
+#[derive(Debug)]
+pub struct Problem {
+    pub code: ErrorCode,
+    pub detail: String,
+    /// Every field of an invalid order that was rejected, and why.
+    pub errors: Vec<Violation>,
+}
+
+/// The members an order's problems have beyond the shared ones.
+#[derive(Serialize)]
+struct Extra<'a> {
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    errors: &'a [Violation],
+}
+
+impl Problem {
+    pub fn new(code: ErrorCode, detail: impl Into<String>) -> Self {
+        Problem { code, detail: detail.into(), errors: Vec::new() }
+    }
+
+    /// An order rejected for the given violations.
+    pub fn invalid(violations: Vec<Violation>) -> Self {
+        Problem { errors: violations, ..Problem::new(ErrorCode::InvalidOrder, "The order is invalid") }
+    }
+
+    pub fn not_found() -> Self {
+        Problem::new(ErrorCode::NotFound, "There is nothing at this path")
+    }
+
+    pub fn unauthorized() -> Self {
+        Problem::new(ErrorCode::Unauthorized, "Admin routes need the configured admin token as a bearer token")
+    }
+
+    /// An internal failure. The cause is logged rather than sent, so that
+    /// nothing about our internals leaks to the client.
+    pub fn internal(cause: impl Display) -> Self {
+        logging::error("internal error", serde_json::json!({"cause": cause.to_string()}));
+        Problem::new(ErrorCode::Internal, "The request could not be handled")
+    }
+
+    /// The problem as a response, with the order's rejected fields if any.
+    pub fn response(&self, correlation_id: &str) -> Response<Body> {
+        problem::response(self.code.status(), self.code, &self.detail, correlation_id, Extra { errors: &self.errors })
+    }
+}
+
+impl From<anyhow::Error> for Problem {
+    fn from(e: anyhow::Error) -> Self {
+        Problem::internal(e)
+    }
+}
+
+impl From<hyper::Error> for Problem {
+    fn from(e: hyper::Error) -> Self {
+        Problem::internal(e)
+    }
+}
+
+impl From<serde_json::Error> for Problem {
+    fn from(e: serde_json::Error) -> Self {
+        Problem::internal(e)
+    }
+}
@@ -0,0 +1,34 @@
+//! What a request's access log line reports beyond the request itself.
+
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+use service_common::trace::TraceContext;
+
+/// A request's correlation ID and trace, and the calls it made to the
+/// sales tax rate service.
+pub struct RequestLog {
+    pub correlation_id: String,
+    /// The request's span, which calls it makes are traced under.
+    pub trace: TraceContext,
+    upstream_calls: AtomicU32,
+    upstream_micros: AtomicU64,
+}
+
+impl RequestLog {
+    pub fn new(correlation_id: String, trace: TraceContext) -> Self {
+        RequestLog { correlation_id, trace, upstream_calls: AtomicU32::new(0), upstream_micros: AtomicU64::new(0) }
+    }
+
+    pub fn record_upstream_call(&self, elapsed: Duration) {
+        self.upstream_calls.fetch_add(1, Ordering::Relaxed);
+        self.upstream_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn upstream_calls(&self) -> u32 {
+        self.upstream_calls.load(Ordering::Relaxed)
+    }
+
+    pub fn upstream_time(&self) -> Duration {
+        Duration::from_micros(self.upstream_micros.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,297 @@
+//! Keeps a slow or flapping sales tax rate service from hanging or failing
+//! `/compute`: lookups are bounded by timeouts, retried with jittered
+//! exponential backoff, and failed fast by a circuit breaker while the
+//! service is unhealthy.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use service_common::{logging, trace};
+use crate::config::{self, CONFIG};
+
+/// How calls to the sales tax rate service are bounded and retried.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// How long to wait for a connection to the service.
+    #[serde(rename = "connect_timeout_ms", with = "config::millis")]
+    pub connect_timeout: Duration,
+    /// How long to wait for the service's whole answer once connected.
+    #[serde(rename = "timeout_ms", with = "config::millis")]
+    pub timeout: Duration,
+    /// How many times to retry a failed lookup.
+    pub retries: u32,
+    /// The backoff before the first retry, doubled for every retry after.
+    #[serde(rename = "backoff_ms", with = "config::millis")]
+    pub backoff: Duration,
+    /// The longest backoff between two retries.
+    #[serde(rename = "max_backoff_ms", with = "config::millis")]
+    pub max_backoff: Duration,
+    /// How many failures in a row open the circuit breaker.
+    pub breaker_threshold: u32,
+    /// How long an open breaker fails fast before trying the service again.
+    #[serde(rename = "breaker_cooldown_ms", with = "config::millis")]
+    pub breaker_cooldown: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            connect_timeout: Duration::from_millis(2000),
+            timeout: Duration::from_millis(5000),
+            retries: 2,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(2000),
+            breaker_threshold: 5,
+            breaker_cooldown: Duration::from_millis(30000),
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref SETTINGS: &'static Settings = &CONFIG.sales_tax_rate.calls;
+
+    /// The breaker guarding the sales tax rate service.
+    pub static ref BREAKER: Breaker = Breaker::new(SETTINGS.breaker_threshold, SETTINGS.breaker_cooldown);
+}
+
+/// The backoff before retry number `retry` (from 0): a random duration up
+/// to the exponential backoff, so that callers retrying together spread out.
+pub fn backoff(retry: u32) -> Duration {
+    let ceiling = SETTINGS.backoff
+        .saturating_mul(2u32.saturating_pow(retry))
+        .min(SETTINGS.max_backoff);
+    ceiling.mul_f64(random_fraction())
+}
+
+/// A random number in `[0, 1)`, good enough to spread retries out.
+fn random_fraction() -> f64 {
+    (trace::random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    /// Calls go through.
+    Closed,
+    /// Calls fail fast until the cooldown is over.
+    Open,
+    /// The cooldown is over and one trial call is let through; its outcome
+    /// closes or reopens the breaker.
+    HalfOpen,
+}
+
+struct BreakerInner {
+    state: BreakerState,
+    failures: u32,
+    /// When the breaker opened, or when its current trial call started.
+    opened_at: Option<Instant>,
+    trial_in_flight: bool,
+}
+
+/// A circuit breaker that opens after `threshold` failures in a row.
+pub struct Breaker {
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<BreakerInner>,
+}
+
+/// A breaker's state, as reported by the status endpoint.
+#[derive(Serialize, Debug)]
+pub struct BreakerStatus {
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    /// How long until an open breaker lets a trial call through.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_in_ms: Option<u64>,
+}
+
+impl Breaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Breaker {
+            threshold: threshold.max(1),
+            cooldown,
+            inner: Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                failures: 0,
+                opened_at: None,
+                trial_in_flight: false,
+            }),
+        }
+    }
+
+    /// Whether a call may go through now.
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => true,
+            // A trial that never reported back, say because its order was
+            // abandoned, is given up on after another cooldown
+            BreakerState::Open | BreakerState::HalfOpen => {
+                let cooled_down = inner.opened_at.is_some_and(|opened_at| opened_at.elapsed() >= self.cooldown);
+                if cooled_down || (inner.state == BreakerState::HalfOpen && !inner.trial_in_flight) {
+                    inner.state = BreakerState::HalfOpen;
+                    inner.opened_at = Some(Instant::now());
+                    inner.trial_in_flight = true;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// Whether calls go through without a trial, so that a failed call may
+    /// be retried.
+    pub fn is_closed(&self) -> bool {
+        self.inner.lock().unwrap().state == BreakerState::Closed
+    }
+
+    /// Records a call that reached a healthy service.
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state != BreakerState::Closed {
+            logging::info("sales tax rate service recovered, closing the circuit breaker", serde_json::json!({}));
+        }
+        inner.state = BreakerState::Closed;
+        inner.failures = 0;
+        inner.opened_at = None;
+        inner.trial_in_flight = false;
+    }
+
+    /// Records a call that failed because the service is unhealthy.
+    pub fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures = inner.failures.saturating_add(1);
+        inner.trial_in_flight = false;
+        let open = match inner.state {
+            BreakerState::Closed => inner.failures >= self.threshold,
+            BreakerState::HalfOpen => true,
+            BreakerState::Open => false,
+        };
+        if open {
+            logging::warn("sales tax rate service failing, opening the circuit breaker", serde_json::json!({
+                "consecutive_failures": inner.failures,
+            }));
+            inner.state = BreakerState::Open;
+            inner.opened_at = Some(Instant::now());
+        }
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let inner = self.inner.lock().unwrap();
+        let retry_in = match (inner.state, inner.opened_at) {
+            (BreakerState::Open, Some(opened_at)) => Some(self.cooldown.saturating_sub(opened_at.elapsed())),
+            _ => None,
+        };
+        BreakerStatus {
+            state: inner.state,
+            consecutive_failures: inner.failures,
+            retry_in_ms: retry_in.map(|retry_in| retry_in.as_millis() as u64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(breaker: &Breaker) -> BreakerState {
+        breaker.status().state
+    }
+
+    #[test]
+    fn failures_in_a_row_open_the_breaker() {
+        let breaker = Breaker::new(3, Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(state(&breaker), BreakerState::Closed);
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert_eq!(state(&breaker), BreakerState::Open);
+        assert!(!breaker.allow());
+        assert!(breaker.status().retry_in_ms.is_some());
+    }
+
+    #[test]
+    fn a_success_resets_the_failure_count() {
+        let breaker = Breaker::new(3, Duration::from_secs(60));
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(state(&breaker), BreakerState::Closed);
+        assert_eq!(breaker.status().consecutive_failures, 2);
+    }
+
+    #[test]
+    fn after_the_cooldown_one_trial_call_goes_through() {
+        let breaker = Breaker::new(1, Duration::ZERO);
+        breaker.record_failure();
+        assert_eq!(state(&breaker), BreakerState::Open);
+        assert!(breaker.allow());
+        assert_eq!(state(&breaker), BreakerState::HalfOpen);
+
+        let breaker = Breaker::new(1, Duration::from_millis(20));
+        breaker.record_failure();
+        assert!(!breaker.allow());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.allow());
+        assert!(!breaker.allow(), "a second call went through while the trial was in flight");
+    }
+
+    #[test]
+    fn only_a_closed_breaker_lets_failed_calls_be_retried() {
+        let breaker = Breaker::new(2, Duration::ZERO);
+        breaker.record_failure();
+        assert!(breaker.is_closed());
+        breaker.record_failure();
+        assert!(!breaker.is_closed());
+        assert!(breaker.allow());
+        assert!(!breaker.is_closed(), "a trial call was retried");
+    }
+
+    #[test]
+    fn a_successful_trial_closes_the_breaker() {
+        let breaker = Breaker::new(1, Duration::ZERO);
+        breaker.record_failure();
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert_eq!(state(&breaker), BreakerState::Closed);
+        assert_eq!(breaker.status().consecutive_failures, 0);
+    }
+
+    #[test]
+    fn a_failed_trial_reopens_the_breaker() {
+        let breaker = Breaker::new(2, Duration::from_millis(20));
+        breaker.record_failure();
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert_eq!(state(&breaker), BreakerState::Open);
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn a_trial_that_never_reports_back_is_given_up_on_after_a_cooldown() {
+        let breaker = Breaker::new(1, Duration::from_millis(20));
+        breaker.record_failure();
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn backoff_stays_under_its_exponential_ceiling() {
+        for retry in 0..8 {
+            let ceiling = SETTINGS.backoff.saturating_mul(2u32.pow(retry)).min(SETTINGS.max_backoff);
+            for _ in 0..20 {
+                assert!(backoff(retry) <= ceiling);
+            }
+        }
+    }
+}
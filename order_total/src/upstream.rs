@@ -0,0 +1,299 @@
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use service_common::logging;
+use service_common::problem::{CORRELATION_ID, REQUEST_ID};
+use service_common::trace::{Span, SpanKind, TraceContext, TRACEPARENT, TRACESTATE};
+use crate::balancer::{Endpoint, BALANCER};
+use crate::config::{self, CONFIG};
+use crate::metrics::METRICS;
+use crate::problem::{ErrorCode, Problem};
+use crate::request_log::RequestLog;
+use crate::resilience::{self, BREAKER, SETTINGS};
+use crate::validate::Violation;
+
+lazy_static! {
+    pub static ref POOL: &'static PoolSettings = &CONFIG.sales_tax_rate.pool;
+}
+
+/// How connections to the sales tax rate service are pooled.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolSettings {
+    /// The most idle connections kept open for reuse.
+    pub max_idle: usize,
+    /// How long an idle connection is kept open.
+    #[serde(rename = "idle_timeout_ms", with = "config::millis")]
+    pub idle_timeout: Duration,
+    /// The TCP keep-alive interval of a connection, or zero for none.
+    #[serde(rename = "keepalive_ms", with = "config::millis")]
+    pub keepalive: Duration,
+}
+
+impl Default for PoolSettings {
+    fn default() -> Self {
+        PoolSettings {
+            max_idle: 32,
+            idle_timeout: Duration::from_millis(90000),
+            keepalive: Duration::from_millis(60000),
+        }
+    }
+}
+
+/// The version of the sales tax rate service's JSON contract we speak.
+const RATE_API_VERSION: u32 = 1;
+
+/// A rate lookup in the sales tax rate service's JSON contract.
+#[derive(Serialize, Debug)]
+pub struct RateRequest<'a> {
+    api_version: u32,
+    pub zip: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<NaiveDate>,
+    pub product_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<&'a str>,
+}
+
+impl<'a> RateRequest<'a> {
+    pub fn new(zip: &'a str, date: Option<NaiveDate>, product_id: i32, category: Option<&'a str>) -> Self {
+        RateRequest { api_version: RATE_API_VERSION, zip, date, product_id, category }
+    }
+}
+
+/// One component of the sales tax rate, as reported by the rate service.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Jurisdiction {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: String,
+    pub code: String,
+    #[serde(with = "rust_decimal::serde::float")]
+    pub rate: Decimal,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub taxability: Option<String>,
+}
+
+/// The sales tax rate service's JSON answer for a zip code.
+#[derive(Deserialize, Clone, Debug)]
+pub struct RateLookup {
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(with = "rust_decimal::serde::float")]
+    pub rate: Decimal,
+    pub jurisdictions: Vec<Jurisdiction>,
+    /// The version of the rate table the rate came from.
+    #[serde(default)]
+    pub table_version: Option<String>,
+}
+
+/// The sales tax rate service's JSON error body.
+#[derive(Deserialize, Debug)]
+struct RateError {
+    code: String,
+}
+
+/// Why a rate could not be looked up.
+#[derive(Debug)]
+pub enum RateFailure {
+    /// The rate service could not be reached.
+    Unavailable,
+    /// The rate service did not answer in time.
+    Timeout,
+    /// The circuit breaker is open, so the rate service was not called.
+    CircuitOpen,
+    /// The rate service answered with something we could not read.
+    Unreadable,
+    /// The order's zip code has no rate on file.
+    NotFound,
+    /// The order's zip code is malformed.
+    InvalidZip,
+    /// The order names a product category the rate service does not know.
+    InvalidCategory,
+    /// The rate service failed the lookup for any other reason, with the
+    /// HTTP status it answered with.
+    Failed(u16),
+}
+
+impl RateFailure {
+    /// Whether the failure says the rate service is unhealthy, rather than
+    /// that the lookup itself was rejected. Only these are retried, and
+    /// count towards opening the circuit breaker.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            RateFailure::Unavailable | RateFailure::Timeout | RateFailure::Unreadable => true,
+            RateFailure::Failed(status) => *status >= 500,
+            RateFailure::CircuitOpen | RateFailure::NotFound | RateFailure::InvalidZip | RateFailure::InvalidCategory => false,
+        }
+    }
+
+    /// A short name for the failure, for metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RateFailure::Unavailable => "unavailable",
+            RateFailure::Timeout => "timeout",
+            RateFailure::CircuitOpen => "circuit_open",
+            RateFailure::Unreadable => "unreadable",
+            RateFailure::NotFound => "not_found",
+            RateFailure::InvalidZip => "invalid_zip",
+            RateFailure::InvalidCategory => "invalid_category",
+            RateFailure::Failed(status) if *status >= 500 => "server_error",
+            RateFailure::Failed(_) => "client_error",
+        }
+    }
+}
+
+impl From<RateFailure> for Problem {
+    fn from(failure: RateFailure) -> Self {
+        match failure {
+            RateFailure::Unavailable => Problem::new(ErrorCode::RateServiceUnavailable, "Cannot connect to sales tax rate service"),
+            RateFailure::Timeout => Problem::new(ErrorCode::RateServiceTimeout, "The sales tax rate service did not answer in time"),
+            RateFailure::CircuitOpen => Problem::new(ErrorCode::CircuitOpen, "The sales tax rate service is failing, so lookups are paused. Try again shortly."),
+            RateFailure::Unreadable => Problem::new(ErrorCode::RateServiceError, "Cannot read response from sales tax rate service"),
+            RateFailure::NotFound => Problem::new(ErrorCode::RateNotFound, "The zip code in the order does not have a corresponding sales tax rate."),
+            RateFailure::InvalidZip => Problem::invalid(vec![Violation {
+                field: "shipping_zip".into(),
+                reason: "is not a valid 5 digit or ZIP+4 zip code".into(),
+            }]),
+            RateFailure::InvalidCategory => Problem::invalid(vec![Violation {
+                field: "category".into(),
+                reason: "is not a known product category".into(),
+            }]),
+            RateFailure::Failed(_) => Problem::new(ErrorCode::RateServiceError, "The sales tax rate service could not look up the rate"),
+        }
+    }
+}
+
+/// The client for the sales tax rate service. It is built once and shared
+/// by every order, so that connections are pooled and kept alive between
+/// lookups.
+pub fn client() -> reqwest::Result<reqwest::Client> {
+    let keepalive = Some(POOL.keepalive).filter(|keepalive| !keepalive.is_zero());
+    reqwest::Client::builder()
+        .connect_timeout(SETTINGS.connect_timeout)
+        .pool_max_idle_per_host(POOL.max_idle)
+        .pool_idle_timeout(POOL.idle_timeout)
+        .tcp_keepalive(keepalive)
+        .build()
+}
+
+/// Looks up a rate from the sales tax rate service, passing the order's
+/// correlation ID and trace context along so the two services' logs and
+/// spans can be matched up, and counting the calls made towards the
+/// request's log line. Each call is recorded as a span of its own.
+///
+/// A lookup only reads, so one that fails because the service is unhealthy
+/// is retried, on another replica while there are untried ones and after a
+/// backoff once every replica has been tried. While the circuit breaker is
+/// open the service is not called at all, and a failure that opens it is
+/// not retried but answered as it is.
+pub async fn fetch_rate(client: &reqwest::Client, request: &RateRequest<'_>, request_log: &RequestLog) -> Result<RateLookup, RateFailure> {
+    if !BREAKER.allow() {
+        METRICS.record_breaker_rejection();
+        return Err(RateFailure::CircuitOpen);
+    }
+    let mut tried = Vec::new();
+    let mut retry = 0;
+    loop {
+        let endpoint = BALANCER.pick(&tried);
+        if !tried.contains(&endpoint) {
+            tried.push(endpoint);
+        }
+        let result = {
+            let in_flight = BALANCER.begin(endpoint);
+            let mut span = Span::start("POST /find_rate", SpanKind::Client, Some(&request_log.trace));
+            span.set("server.address", in_flight.endpoint.name.as_str());
+            span.set("retry", retry);
+            let started = Instant::now();
+            let result = fetch_rate_once(client, in_flight.endpoint, request, &request_log.correlation_id, span.context()).await;
+            let elapsed = started.elapsed();
+            let outcome = result.as_ref().map_or_else(RateFailure::kind, |_| "ok");
+            span.set("rate.outcome", outcome);
+            if !matches!(result, Ok(_) | Err(RateFailure::NotFound | RateFailure::InvalidZip | RateFailure::InvalidCategory)) {
+                span.fail(outcome);
+            }
+            span.end();
+            METRICS.record_upstream_call(&in_flight.endpoint.name, outcome, elapsed);
+            request_log.record_upstream_call(elapsed);
+            logging::debug("rate lookup", serde_json::json!({
+                "correlation_id": request_log.correlation_id,
+                "endpoint": in_flight.endpoint.name,
+                "outcome": outcome,
+                "latency_ms": logging::millis(elapsed),
+            }));
+            result
+        };
+        match &result {
+            Err(failure) if failure.is_transient() => {
+                BREAKER.record_failure();
+                BALANCER.record_failure(endpoint);
+                if retry < SETTINGS.retries && BREAKER.is_closed() {
+                    if tried.len() >= BALANCER.count() {
+                        tokio::time::sleep(resilience::backoff(retry)).await;
+                    }
+                    retry += 1;
+                    continue;
+                }
+            }
+            _ => {
+                BREAKER.record_success();
+                BALANCER.record_success(endpoint);
+            }
+        }
+        return result;
+    }
+}
+
+async fn fetch_rate_once(client: &reqwest::Client, endpoint: &Endpoint, request: &RateRequest<'_>, correlation_id: &str, trace: &TraceContext) -> Result<RateLookup, RateFailure> {
+    let failed = |failure: RateFailure, error: &dyn Display| {
+        logging::warn("rate lookup failed", serde_json::json!({
+            "correlation_id": correlation_id,
+            "endpoint": endpoint.name,
+            "reason": failure.kind(),
+            "error": error.to_string(),
+        }));
+        failure
+    };
+
+    let mut sent_request = client.post(&endpoint.url)
+        .header(CORRELATION_ID, correlation_id)
+        .header(REQUEST_ID, correlation_id)
+        .header(TRACEPARENT, trace.traceparent());
+    if let Some(state) = &trace.state {
+        sent_request = sent_request.header(TRACESTATE, state);
+    }
+    let sent_request = sent_request
+        .timeout(SETTINGS.timeout)
+        .json(request)
+        .send()
+        .await;
+
+    let response = match sent_request {
+        Ok(response) => response,
+        Err(e) if e.is_timeout() => return Err(failed(RateFailure::Timeout, &e.without_url())),
+        Err(e) => return Err(failed(RateFailure::Unavailable, &e.without_url())),
+    };
+
+    let status = response.status();
+    let body_text = match response.text().await {
+        Ok(text) => text,
+        Err(e) if e.is_timeout() => return Err(failed(RateFailure::Timeout, &e.without_url())),
+        Err(e) => return Err(failed(RateFailure::Unreadable, &e.without_url())),
+    };
+
+    // A zip code with no rate, a malformed one or an unknown category is an
+    // answer rather than a failure worth a warning
+    if !status.is_success() {
+        let code = serde_json::from_str::<RateError>(&body_text).map(|e| e.code).unwrap_or_default();
+        return Err(match code.as_str() {
+            "rate_not_found" => RateFailure::NotFound,
+            "invalid_zip" => RateFailure::InvalidZip,
+            "invalid_category" => RateFailure::InvalidCategory,
+            _ => failed(RateFailure::Failed(status.as_u16()), &format_args!("answered {}: {}", status, body_text)),
+        });
+    }
+
+    serde_json::from_str(&body_text).map_err(|e| failed(RateFailure::Unreadable, &e))
+}
@@ -0,0 +1,354 @@
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use crate::config::CONFIG;
+use crate::order::Order;
+use crate::problem::{ErrorCode, Problem};
+
+/// The fields an order may hold. `tax` and `tax_breakdown` are only ever
+/// written out, but are accepted so that a computed order can be sent again.
+const ORDER_FIELDS: &[&str] = &[
+    "order_id", "product_id", "quantity", "line_items", "subtotal", "currency",
+    "shipping_address", "shipping_zip", "order_date", "category", "tax", "total",
+    "tax_breakdown", "tax_estimated", "tax_estimate_reason",
+];
+
+/// The fields a line item may hold, with its computed ones accepted likewise.
+const LINE_ITEM_FIELDS: &[&str] = &[
+    "product_id", "quantity", "unit_price", "discount", "category", "subtotal",
+    "tax", "total", "tax_breakdown", "tax_estimated",
+];
+
+/// The largest amount an order may hold, and the largest price a line may
+/// come to. Far above any real order, and far enough below what `Decimal`
+/// can hold that adding up the lines and their tax cannot overflow.
+const MAX_AMOUNT: i64 = 1_000_000_000_000;
+
+/// One thing wrong with an order: the path of the offending field, such as
+/// `line_items[0].quantity`, and why it was rejected. The path is empty when
+/// the body as a whole is at fault.
+#[derive(Serialize, Debug)]
+pub struct Violation {
+    pub field: String,
+    pub reason: String,
+}
+
+/// Parses and validates an order, collecting every problem with it rather
+/// than stopping at the first.
+pub fn parse_order(bytes: &[u8]) -> Result<Order, Problem> {
+    let value: Value = serde_json::from_slice(bytes)
+        .map_err(|e| Problem::new(ErrorCode::MalformedJson, format!("The body is not valid JSON: {}", e)))?;
+
+    let mut checker = Checker::default();
+    match value.as_object() {
+        Some(fields) => checker.order(fields),
+        None => checker.violation("", "must be a JSON object"),
+    }
+    if !checker.violations.is_empty() {
+        return Err(Problem::invalid(checker.violations));
+    }
+    // Anything the checks above let through should deserialize, but a
+    // mismatch must still not take the handler down
+    serde_json::from_value(value).map_err(|e| {
+        Problem::invalid(vec![Violation { field: String::new(), reason: e.to_string() }])
+    })
+}
+
+#[derive(Default)]
+struct Checker {
+    violations: Vec<Violation>,
+}
+
+impl Checker {
+    fn violation(&mut self, field: &str, reason: impl Into<String>) {
+        self.violations.push(Violation { field: field.into(), reason: reason.into() });
+    }
+
+    fn order(&mut self, fields: &Map<String, Value>) {
+        self.unknown_fields(fields, ORDER_FIELDS, "");
+
+        self.required::<i32>(fields, "order_id", "");
+        self.optional::<i32>(fields, "product_id", "");
+        self.non_negative_count(fields, "quantity", "", false);
+        self.non_negative_amount(fields, "subtotal", "");
+        self.optional::<Decimal>(fields, "total", "");
+
+        if let Some(currency) = self.optional::<String>(fields, "currency", "") {
+            if currency.len() != 3 || !currency.bytes().all(|b| b.is_ascii_uppercase()) {
+                self.violation("currency", "must be a 3 letter ISO 4217 currency code, such as USD");
+            }
+        }
+        if let Some(address) = self.required::<String>(fields, "shipping_address", "") {
+            if address.trim().is_empty() {
+                self.violation("shipping_address", "must not be empty");
+            }
+        }
+        if let Some(zip) = self.required::<String>(fields, "shipping_zip", "") {
+            if !is_zip(&zip) {
+                self.violation("shipping_zip", "must be a 5 digit or ZIP+4 zip code");
+            }
+        }
+        self.optional::<NaiveDate>(fields, "order_date", "");
+        self.category(fields, "");
+
+        let single_product = !matches!(fields.get("product_id"), None | Some(Value::Null));
+        let has_lines = matches!(fields.get("line_items"), Some(Value::Array(items)) if !items.is_empty());
+        if !has_lines {
+            if !single_product {
+                self.violation("line_items", "an order needs line_items, or a product_id, quantity and subtotal");
+            } else if matches!(fields.get("subtotal"), None | Some(Value::Null)) {
+                // A single product order is priced by its subtotal alone
+                self.violation("subtotal", "is required");
+            }
+        }
+        match fields.get("line_items") {
+            None | Some(Value::Null) => {}
+            // Too many lines are not checked one by one
+            Some(Value::Array(items)) if items.len() > CONFIG.compute.max_line_items => {
+                self.violation("line_items", format!("must not hold more than {} items", CONFIG.compute.max_line_items));
+            }
+            Some(Value::Array(items)) => {
+                for (i, item) in items.iter().enumerate() {
+                    let path = format!("line_items[{}]", i);
+                    match item.as_object() {
+                        Some(item) => self.line_item(item, &path),
+                        None => self.violation(&path, "must be an object"),
+                    }
+                }
+            }
+            Some(_) => self.violation("line_items", "must be an array"),
+        }
+    }
+
+    fn line_item(&mut self, fields: &Map<String, Value>, path: &str) {
+        self.unknown_fields(fields, LINE_ITEM_FIELDS, path);
+        self.required::<i32>(fields, "product_id", path);
+        let quantity = self.non_negative_count(fields, "quantity", path, true);
+        if matches!(fields.get("unit_price"), None | Some(Value::Null)) {
+            self.violation(&field_path(path, "unit_price"), "is required");
+        }
+        let unit_price = self.non_negative_amount(fields, "unit_price", path);
+        let discount = self.non_negative_amount(fields, "discount", path);
+        if let (Some(quantity), Some(unit_price)) = (quantity, unit_price) {
+            match unit_price.checked_mul(Decimal::from(quantity)).filter(|price| *price <= Decimal::from(MAX_AMOUNT)) {
+                None => self.violation(&field_path(path, "unit_price"), format!("times quantity must not be more than {}", MAX_AMOUNT)),
+                Some(price) if discount.is_some_and(|discount| discount > price) => {
+                    self.violation(&field_path(path, "discount"), "must not be more than the line's price");
+                }
+                Some(_) => {}
+            }
+        }
+        self.category(fields, path);
+    }
+
+    fn unknown_fields(&mut self, fields: &Map<String, Value>, known: &[&str], path: &str) {
+        for name in fields.keys().filter(|name| !known.contains(&name.as_str())) {
+            self.violation(&field_path(path, name), "is not a known field");
+        }
+    }
+
+    fn category(&mut self, fields: &Map<String, Value>, path: &str) {
+        if let Some(category) = self.optional::<String>(fields, "category", path) {
+            if category.trim().is_empty() {
+                self.violation(&field_path(path, "category"), "must not be empty");
+            }
+        }
+    }
+
+    fn non_negative_count(&mut self, fields: &Map<String, Value>, name: &str, path: &str, required: bool) -> Option<i32> {
+        let quantity = if required {
+            self.required::<i32>(fields, name, path)
+        } else {
+            self.optional::<i32>(fields, name, path)
+        };
+        if quantity.is_some_and(|quantity| quantity < 0) {
+            self.violation(&field_path(path, name), "must not be negative");
+            return None;
+        }
+        quantity
+    }
+
+    fn non_negative_amount(&mut self, fields: &Map<String, Value>, name: &str, path: &str) -> Option<Decimal> {
+        let amount = self.optional::<Decimal>(fields, name, path);
+        if amount.is_some_and(|amount| amount < Decimal::ZERO) {
+            self.violation(&field_path(path, name), "must not be negative");
+            return None;
+        }
+        if amount.is_some_and(|amount| amount > Decimal::from(MAX_AMOUNT)) {
+            self.violation(&field_path(path, name), format!("must not be more than {}", MAX_AMOUNT));
+            return None;
+        }
+        amount
+    }
+
+    /// Reads a field that must be present.
+    fn required<T: DeserializeOwned + Expected>(&mut self, fields: &Map<String, Value>, name: &str, path: &str) -> Option<T> {
+        match fields.get(name) {
+            None | Some(Value::Null) => {
+                self.violation(&field_path(path, name), "is required");
+                None
+            }
+            Some(_) => self.optional(fields, name, path),
+        }
+    }
+
+    /// Reads a field that may be left out, recording a violation when it is
+    /// present with the wrong type.
+    fn optional<T: DeserializeOwned + Expected>(&mut self, fields: &Map<String, Value>, name: &str, path: &str) -> Option<T> {
+        let value = fields.get(name).filter(|value| !value.is_null())?;
+        match T::deserialize(value) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                self.violation(&field_path(path, name), format!("must be {}", T::EXPECTED));
+                None
+            }
+        }
+    }
+}
+
+/// What a field of each type must look like, for violation reasons.
+trait Expected {
+    const EXPECTED: &'static str;
+}
+
+impl Expected for i32 {
+    const EXPECTED: &'static str = "a whole number";
+}
+
+impl Expected for String {
+    const EXPECTED: &'static str = "a string";
+}
+
+impl Expected for Decimal {
+    const EXPECTED: &'static str = "a decimal amount";
+}
+
+impl Expected for NaiveDate {
+    const EXPECTED: &'static str = "a date in YYYY-MM-DD format";
+}
+
+fn field_path(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+/// Whether a zip code is 5 digits or ZIP+4, as `78701-1234` or `787011234`,
+/// ignoring surrounding whitespace.
+fn is_zip(zip: &str) -> bool {
+    let zip = zip.trim().as_bytes();
+    let digits = |bytes: &[u8]| bytes.iter().all(u8::is_ascii_digit);
+    match zip.len() {
+        5 | 9 => digits(zip),
+        10 => zip[5] == b'-' && digits(&zip[..5]) && digits(&zip[6..]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    /// The violations of an order, as `field: reason`.
+    fn violations(order: Value) -> Vec<String> {
+        match parse_order(order.to_string().as_bytes()) {
+            Ok(_) => Vec::new(),
+            Err(problem) => {
+                assert_eq!(problem.code, ErrorCode::InvalidOrder);
+                problem.errors.iter().map(|violation| format!("{}: {}", violation.field, violation.reason)).collect()
+            }
+        }
+    }
+
+    fn order(line_items: Value) -> Value {
+        json!({"order_id": 1, "shipping_address": "1 Main St", "shipping_zip": "78701", "line_items": line_items})
+    }
+
+    fn line() -> Value {
+        json!({"product_id": 321, "quantity": 2, "unit_price": "10"})
+    }
+
+    #[test]
+    fn valid_orders_have_no_violations() {
+        assert!(violations(order(json!([line(), {"product_id": 101, "quantity": 1, "unit_price": 5, "category": "grocery"}]))).is_empty());
+        let single_product = json!({"order_id": 1, "product_id": 321, "quantity": 2, "subtotal": 20.0, "shipping_address": "1 Main St", "shipping_zip": "78701-1234"});
+        assert!(violations(single_product).is_empty());
+    }
+
+    #[test]
+    fn every_violation_is_listed_at_once() {
+        let order = json!({"order_id": "one", "shipping_zip": "7870", "currency": "usd", "colour": "red", "line_items": [line()]});
+        let mut found = violations(order);
+        found.sort();
+        assert_eq!(found, [
+            "colour: is not a known field",
+            "currency: must be a 3 letter ISO 4217 currency code, such as USD",
+            "order_id: must be a whole number",
+            "shipping_address: is required",
+            "shipping_zip: must be a 5 digit or ZIP+4 zip code",
+        ]);
+    }
+
+    #[test]
+    fn line_item_violations_name_the_line_and_field() {
+        let found = violations(order(json!([
+            line(),
+            {"product_id": 7, "quantity": -1, "unit_price": "abc", "size": "L"},
+            "not a line",
+            {"product_id": 8, "quantity": 1, "unit_price": 5, "discount": 6, "category": " "},
+        ])));
+        assert_eq!(found, [
+            "line_items[1].size: is not a known field",
+            "line_items[1].quantity: must not be negative",
+            "line_items[1].unit_price: must be a decimal amount",
+            "line_items[2]: must be an object",
+            "line_items[3].discount: must not be more than the line's price",
+            "line_items[3].category: must not be empty",
+        ]);
+    }
+
+    #[test]
+    fn an_order_needs_lines_or_a_single_product() {
+        assert_eq!(violations(order(json!([]))), ["line_items: an order needs line_items, or a product_id, quantity and subtotal"]);
+        assert_eq!(violations(order(json!({}))), [
+            "line_items: an order needs line_items, or a product_id, quantity and subtotal",
+            "line_items: must be an array",
+        ]);
+        let no_subtotal = json!({"order_id": 1, "product_id": 321, "shipping_address": "1 Main St", "shipping_zip": "78701"});
+        assert_eq!(violations(no_subtotal), ["subtotal: is required"]);
+    }
+
+    #[test]
+    fn amounts_are_bounded() {
+        let at_most = |amount: i64| json!({"product_id": 1, "quantity": 1, "unit_price": amount});
+        assert!(violations(order(json!([at_most(MAX_AMOUNT)]))).is_empty());
+        assert_eq!(violations(order(json!([at_most(MAX_AMOUNT + 1)]))), [
+            format!("line_items[0].unit_price: must not be more than {}", MAX_AMOUNT),
+        ]);
+        let too_many = json!({"product_id": 1, "quantity": 3, "unit_price": MAX_AMOUNT / 2});
+        assert_eq!(violations(order(json!([too_many]))), [
+            format!("line_items[0].unit_price: times quantity must not be more than {}", MAX_AMOUNT),
+        ]);
+    }
+
+    #[test]
+    fn an_order_holds_at_most_max_line_items() {
+        let max = CONFIG.compute.max_line_items;
+        assert!(violations(order(Value::Array(vec![line(); max]))).is_empty());
+        assert_eq!(violations(order(Value::Array(vec![line(); max + 1]))), [
+            format!("line_items: must not hold more than {} items", max),
+        ]);
+    }
+
+    #[test]
+    fn a_body_that_is_not_json_is_malformed() {
+        let problem = parse_order(b"{").unwrap_err();
+        assert_eq!(problem.code, ErrorCode::MalformedJson);
+        assert!(parse_order(b"[]").unwrap_err().errors.iter().any(|violation| violation.field.is_empty()));
+    }
+}
@@ -0,0 +1,170 @@
+//! The versioned JSON contract of `/find_rate` and `/find_rates`. A request
+//! with a JSON body gets a JSON response; a bare zip code in the body keeps
+//! the original plain-text contract of `/find_rate`.
+
+use chrono::NaiveDate;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response};
+use serde::{Deserialize, Serialize};
+use crate::metrics::METRICS;
+use crate::problem::{ErrorCode, Problem};
+use crate::rates::{self, RateTable, ZipRate};
+use crate::taxability::Categories;
+use crate::zip::ZipCode;
+
+/// The version of the JSON contract, reported in every JSON response.
+pub const API_VERSION: u32 = 1;
+
+/// What to look up: a zip code, optionally as of a `YYYY-MM-DD` date
+/// (today by default) and for a product category. Without a category, the
+/// category on file for `product_id` applies.
+#[derive(Deserialize, Debug)]
+pub struct RateQuery {
+    pub zip: String,
+    #[serde(default)]
+    pub date: Option<String>,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub product_id: Option<i64>,
+}
+
+/// A JSON rate lookup.
+#[derive(Deserialize, Debug)]
+pub struct RateRequest {
+    #[serde(default)]
+    pub api_version: Option<u32>,
+    #[serde(flatten)]
+    pub query: RateQuery,
+}
+
+/// A successful rate lookup: the normalized zip code, the category taxed
+/// under and the rate with that category's rules applied.
+pub struct RateLookup {
+    pub zip: ZipCode,
+    pub category: Option<String>,
+    pub rate: ZipRate,
+}
+
+/// A successful JSON rate lookup.
+#[derive(Serialize)]
+pub struct RateResponse<'a> {
+    pub api_version: u32,
+    pub zip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(flatten)]
+    pub rate: ZipRate,
+    pub table_version: &'a str,
+}
+
+/// A batch of JSON rate lookups. Items are kept as raw JSON so that one
+/// malformed item fails on its own instead of failing the whole batch.
+#[derive(Deserialize, Debug)]
+pub struct BatchRequest {
+    #[serde(default)]
+    pub api_version: Option<u32>,
+    pub items: Vec<serde_json::Value>,
+}
+
+/// The results of a batch, in the order the items were given.
+#[derive(Serialize)]
+pub struct BatchResponse<'a> {
+    pub api_version: u32,
+    pub table_version: &'a str,
+    pub results: Vec<BatchResult>,
+}
+
+/// The result of one lookup in a batch.
+#[derive(Serialize)]
+pub struct BatchResult {
+    pub zip: Option<String>,
+    #[serde(flatten)]
+    pub outcome: BatchOutcome,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum BatchOutcome {
+    Ok {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        category: Option<String>,
+        #[serde(flatten)]
+        rate: ZipRate,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+/// Why a single lookup failed.
+#[derive(Debug)]
+pub struct LookupError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl From<LookupError> for Problem {
+    fn from(e: LookupError) -> Self {
+        Problem::new(e.code, e.message)
+    }
+}
+
+/// Looks up the rate for a query, with the rules for its category applied.
+/// A malformed zip code is an `InvalidZip` error, kept apart from a
+/// well-formed zip code that has no rate on file, and a category no product
+/// or rule names is an `InvalidCategory` error.
+pub fn lookup(rates: &RateTable, categories: &Categories, query: &RateQuery) -> Result<RateLookup, LookupError> {
+    let zip = ZipCode::parse(&query.zip).ok_or_else(|| LookupError {
+        code: ErrorCode::InvalidZip,
+        message: format!("{:?} is not a 5 digit or ZIP+4 zip code", query.zip),
+    })?;
+    let date = match query.date.as_deref().map(str::parse::<NaiveDate>) {
+        Some(Ok(date)) => date,
+        Some(Err(_)) => return Err(LookupError {
+            code: ErrorCode::InvalidDate,
+            message: "The date must be formatted as YYYY-MM-DD".to_owned(),
+        }),
+        None => rates::today(),
+    };
+    let category = categories.resolve(query.category.as_deref(), query.product_id).map_err(|category| LookupError {
+        code: ErrorCode::InvalidCategory,
+        message: format!("{:?} is not a known product category", category),
+    })?;
+    let rate = rates.find(&zip, date);
+    METRICS.record_lookup(zip.prefix(), rate.is_some());
+    let rate = rate.ok_or_else(|| LookupError {
+        code: ErrorCode::RateNotFound,
+        message: format!("No sales tax rate is on file for zip code {} on {}", zip, date),
+    })?;
+
+    let rate = match &category {
+        Some(category) => categories.apply(rate, category),
+        None => rate.clone(),
+    };
+    Ok(RateLookup { zip, category, rate })
+}
+
+/// Looks up one raw batch item.
+pub fn lookup_item(rates: &RateTable, categories: &Categories, item: serde_json::Value) -> BatchResult {
+    let zip = item.get("zip").and_then(|zip| zip.as_str()).map(str::to_owned);
+    let outcome = match serde_json::from_value::<RateQuery>(item) {
+        Ok(query) => match lookup(rates, categories, &query) {
+            Ok(found) => BatchOutcome::Ok { category: found.category, rate: found.rate },
+            Err(e) => BatchOutcome::Error { code: e.code, message: e.message },
+        },
+        Err(e) => BatchOutcome::Error {
+            code: ErrorCode::InvalidRequest,
+            message: format!("Invalid batch item: {}", e),
+        },
+    };
+    BatchResult { zip, outcome }
+}
+
+/// Builds a JSON response for the JSON contract.
+pub fn json_response(body: &impl Serialize) -> Result<Response<Body>, Problem> {
+    let mut res = Response::new(Body::from(serde_json::to_string(body)?));
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Ok(res)
+}
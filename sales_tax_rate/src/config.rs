@@ -0,0 +1,125 @@
+//! The service's configuration. Every setting has a default, which a TOML
+//! file named by `SALES_TAX_RATE_CONFIG` may change, and an environment
+//! variable may change again. It is all checked at startup, so a bad
+//! setting stops the service instead of failing lookups later.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use service_common::config::{self as common, millis, AdminConfig, CorsConfig, LogConfig};
+use service_common::trace::TracingSettings;
+
+/// The environment variable naming the configuration file.
+const CONFIG_FILE: &str = "SALES_TAX_RATE_CONFIG";
+
+/// The environment variables that override settings, and the settings'
+/// keys in the configuration file. A list is separated by commas.
+const OVERRIDES: &[(&str, &str)] = &[
+    ("SALES_TAX_RATE_BIND", "server.bind"),
+    ("SALES_TAX_RATE_PORT", "server.port"),
+    ("SALES_TAX_RATE_DRAIN_MS", "server.drain_ms"),
+    ("SALES_TAX_RATE_CORS_ORIGINS", "cors.allowed_origins"),
+    ("SALES_TAX_RATE_ADMIN_TOKEN", "admin.token"),
+    ("SALES_TAX_RATE_LOG_LEVEL", "log.level"),
+    ("SALES_TAX_RATE_TRACING_EXPORTER", "tracing.exporter"),
+    ("SALES_TAX_RATE_TRACING_FILE", "tracing.file"),
+    ("SALES_TAX_RATE_TRACING_OTLP_ENDPOINT", "tracing.otlp_endpoint"),
+    ("SALES_TAX_RATE_TRACING_FLUSH_MS", "tracing.flush_ms"),
+    ("SALES_TAX_RATE_FILE", "rates.file"),
+    ("SALES_TAX_PRODUCT_FILE", "rates.product_file"),
+    ("SALES_TAX_CATEGORY_RULES_FILE", "rates.category_rules_file"),
+    ("SALES_TAX_RATE_RELOAD_SECS", "rates.reload_secs"),
+    ("SALES_TAX_RATE_MAX_BATCH", "rates.max_batch"),
+];
+
+lazy_static! {
+    pub static ref CONFIG: Config = Config::load().unwrap_or_else(|e| panic!("Invalid configuration: {:#}", e));
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub cors: CorsConfig,
+    pub admin: AdminConfig,
+    pub log: LogConfig,
+    pub tracing: TracingSettings,
+    pub rates: RatesConfig,
+}
+
+/// Where the service listens.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// How long requests in flight may take to finish once shutting down
+    /// starts.
+    #[serde(rename = "drain_ms", with = "millis")]
+    pub drain: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED), port: 8001, drain: Duration::from_millis(30000) }
+    }
+}
+
+impl ServerConfig {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+}
+
+/// Where rates and product categories come from, and how lookups are
+/// bounded.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RatesConfig {
+    /// The rate table, instead of the one compiled into the binary.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// The product categories, instead of the compiled-in ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product_file: Option<PathBuf>,
+    /// The category rules, instead of the compiled-in ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category_rules_file: Option<PathBuf>,
+    /// How often the rate file is checked for changes, or zero for never.
+    pub reload_secs: u64,
+    /// The most lookups a single `/find_rates` batch may hold.
+    pub max_batch: usize,
+}
+
+impl Default for RatesConfig {
+    fn default() -> Self {
+        RatesConfig {
+            file: None,
+            product_file: None,
+            category_rules_file: None,
+            reload_secs: 30,
+            max_batch: 100,
+        }
+    }
+}
+
+impl Config {
+    fn load() -> anyhow::Result<Config> {
+        common::load(CONFIG_FILE, OVERRIDES, Config::check)
+    }
+
+    /// Adds a problem for every setting that has the right type but a value
+    /// that cannot work.
+    fn check(&self, problems: &mut Vec<String>) {
+        if self.server.port == 0 {
+            problems.push("server.port must not be 0".into());
+        }
+        self.cors.check(problems);
+        self.admin.check(problems);
+        self.tracing.check(problems);
+        if self.rates.max_batch == 0 {
+            problems.push("rates.max_batch must be at least 1".into());
+        }
+    }
+}
@@ -1,66 +1,309 @@
-use std::net::SocketAddr;
+#[macro_use]
+extern crate lazy_static;
+
+mod api;
+mod config;
+mod metrics;
+mod problem;
+mod rates;
+mod taxability;
+mod zip;
+
 use std::convert::Infallible;
 use std::str;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Method, Request, Response, StatusCode, Server};
-use csv::Reader;
+use hyper::header::{HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE, ORIGIN};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use service_common::logging;
+use service_common::problem::{correlation_id, CORRELATION_ID, REQUEST_ID};
+use service_common::shutdown::{self, SHUTDOWN};
+use service_common::trace::{self, Span, SpanKind, TraceContext};
+use api::{BatchRequest, BatchResponse, RateQuery, RateRequest, RateResponse, API_VERSION};
+use config::CONFIG;
+use metrics::METRICS;
+use problem::{ErrorCode, Problem};
+use rates::RateStore;
+use taxability::Categories;
+
+/// This is our service handler. It receives a Request, and answers it with
+/// CORS headers and its correlation ID whether it succeeds or fails, then
+/// logs it and records its span.
+async fn serve(req: Request<Body>, store: Arc<RateStore>, categories: Arc<Categories>) -> Result<Response<Body>, Infallible> {
+    let _in_flight = SHUTDOWN.begin();
+    let started = Instant::now();
+    let method = req.method().clone();
+    let route = metrics::route(req.uri().path());
+    let mut span = Span::start(format!("{} {}", method, route), SpanKind::Server, TraceContext::from_headers(req.headers()).as_ref());
+    span.set("http.request.method", method.as_str());
+    span.set("http.route", route);
+    let correlation_id = correlation_id(&req);
+    span.set("correlation_id", correlation_id.as_str());
+    let origin = req.headers().get(ORIGIN).cloned();
+    let mut error = None;
+    let mut res = match handle_request(req, store, categories).await {
+        Ok(res) => res,
+        Err(problem) => {
+            error = Some(problem.code);
+            problem.response(&correlation_id)
+        }
+    };
+    let elapsed = started.elapsed();
+    let status = res.status().as_u16();
+    METRICS.record_request(method.as_str(), route, status, elapsed);
+    let mut fields = serde_json::json!({
+        "correlation_id": correlation_id,
+        "trace_id": span.context().trace_id(),
+        "method": method.as_str(),
+        "route": route,
+        "status": status,
+        "latency_ms": logging::millis(elapsed),
+    });
+    span.set("http.response.status_code", status);
+    if let Some(code) = error {
+        let code = serde_json::json!(code);
+        if status >= 500 {
+            span.fail(code.as_str().unwrap_or_default());
+        }
+        span.set("error.type", code.clone());
+        fields["error"] = code;
+    }
+    logging::info("request", fields);
+    span.end();
+    let headers = res.headers_mut();
+    CONFIG.cors.add_headers(origin.as_ref(), headers);
+    if let Ok(value) = HeaderValue::from_str(&correlation_id) {
+        headers.insert(CORRELATION_ID, value.clone());
+        headers.insert(REQUEST_ID, value);
+    }
+    Ok(res)
+}
 
-/// This is our service handler. It receives a Request, routes on its
-/// path, and returns a Future of a Response.
-async fn handle_request(req: Request<Body>) -> Result<Response<Body>, anyhow::Error> {
+/// Routes a request on its path, and returns a Future of a Response.
+async fn handle_request(req: Request<Body>, store: Arc<RateStore>, categories: Arc<Categories>) -> Result<Response<Body>, Problem> {
+    // Admin routes need the admin token, and are off without one. A browser
+    // cannot send it cross-origin, as `Authorization` is not an allowed header.
+    if req.uri().path().starts_with("/admin/") && req.method() != Method::OPTIONS && !CONFIG.admin.authorizes(req.headers()) {
+        return Err(Problem::unauthorized());
+    }
     match (req.method(), req.uri().path()) {
+        // CORS preflight
+        (&Method::OPTIONS, _) => Ok(Response::new(Body::empty())),
+
         // Serve some instructions at /
         (&Method::GET, "/") => Ok(Response::new(Body::from(
-            "Try POSTing data to /find_rate such as: `curl localhost:8001/get_rate -XPOST -d '78701'`",
+            "Try POSTing data to /find_rate such as: `curl localhost:8001/find_rate?date=2024-01-01 -XPOST -d '78701'`",
         ))),
 
+        // Liveness: the process is up and answering
+        (&Method::GET, "/healthz") => api::json_response(&serde_json::json!({"status": "ok"})),
+
+        // Readiness: a validated rate table is loaded, so lookups can be answered
+        (&Method::GET, "/readyz") => {
+            let rates = store.current();
+            let ready = rates.len() > 0;
+            // A service shutting down takes no new work, whatever its checks say
+            let shutting_down = SHUTDOWN.is_requested();
+            let mut res = api::json_response(&serde_json::json!({
+                "status": if shutting_down { "shutting_down" } else if ready { "ready" } else { "not_ready" },
+                "checks": {
+                    "rate_table": {
+                        "status": if ready { "up" } else { "down" },
+                        "rates": rates.len(),
+                        "version": rates.version(),
+                    },
+                },
+            }))?;
+            if shutting_down || !ready {
+                *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            }
+            Ok(res)
+        }
+
+        // Prometheus metrics
+        (&Method::GET, "/metrics") => {
+            let mut res = Response::new(Body::from(METRICS.render(&store.current())));
+            res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4"));
+            Ok(res)
+        }
+
         (&Method::POST, "/find_rate") => {
+            // A JSON body selects the JSON contract. A plain-text zip code
+            // may still ask for a JSON response through `Accept`.
+            let json_body = accepts_json(&req, CONTENT_TYPE);
+            let json = json_body || accepts_json(&req, ACCEPT);
+            let query_date = query_param(&req, "date").map(str::to_owned);
             let post_body = hyper::body::to_bytes(req.into_body()).await?;
-            let mut rate = "".to_string();
-
-            let rates_data: &[u8] = include_bytes!("rates_by_zipcode.csv");
-            let mut rdr = Reader::from_reader(rates_data);
-            for result in rdr.records() {
-                let record = result?;
-                // dbg!("{:?}", record.clone());
-                if str::from_utf8(&post_body).unwrap().eq(&record[0]) {
-                    rate = record[1].to_string();
-                    break;
+
+            let request = if json_body {
+                match serde_json::from_slice::<RateRequest>(&post_body) {
+                    Ok(request) => request,
+                    Err(e) => return Err(Problem::new(ErrorCode::InvalidRequest, format!("Invalid rate request: {}", e))),
                 }
+            } else {
+                RateRequest {
+                    api_version: None,
+                    query: RateQuery {
+                        zip: str::from_utf8(&post_body).unwrap_or("").to_owned(),
+                        date: query_date,
+                        category: None,
+                        product_id: None,
+                    },
+                }
+            };
+            if request.api_version.is_some_and(|version| version != API_VERSION) {
+                let message = format!("Only api_version {} is supported", API_VERSION);
+                return Err(Problem::new(ErrorCode::UnsupportedApiVersion, message));
             }
 
-            if rate.is_empty() {
-                let mut not_found = Response::default();
-                *not_found.status_mut() = StatusCode::NOT_FOUND;
-                Ok(not_found)
-            } else {
-                Ok(Response::new(Body::from(rate)))
+            let rates = store.current();
+            match api::lookup(&rates, &categories, &request.query) {
+                Ok(found) if json => api::json_response(&RateResponse {
+                    api_version: API_VERSION,
+                    zip: found.zip.to_string(),
+                    category: found.category,
+                    rate: found.rate,
+                    table_version: rates.version(),
+                }),
+                Ok(found) => Ok(Response::new(Body::from(found.rate.rate.to_string()))),
+                Err(e) => Err(Problem::from(e).negotiated(json)),
             }
         }
 
-        // Return the 404 Not Found for other routes.
-        _ => {
-            let mut not_found = Response::default();
-            *not_found.status_mut() = StatusCode::NOT_FOUND;
-            Ok(not_found)
+        // Look up many zip codes at once, failing items one by one
+        (&Method::POST, "/find_rates") => {
+            let post_body = hyper::body::to_bytes(req.into_body()).await?;
+            let batch = match serde_json::from_slice::<BatchRequest>(&post_body) {
+                Ok(batch) => batch,
+                Err(e) => return Err(Problem::new(ErrorCode::InvalidRequest, format!("Invalid batch request: {}", e))),
+            };
+            if batch.api_version.is_some_and(|version| version != API_VERSION) {
+                let message = format!("Only api_version {} is supported", API_VERSION);
+                return Err(Problem::new(ErrorCode::UnsupportedApiVersion, message));
+            }
+            if batch.items.len() > CONFIG.rates.max_batch {
+                let message = format!("A batch may hold at most {} items", CONFIG.rates.max_batch);
+                return Err(Problem::new(ErrorCode::BatchTooLarge, message));
+            }
+
+            let rates = store.current();
+            api::json_response(&BatchResponse {
+                api_version: API_VERSION,
+                table_version: rates.version(),
+                results: batch.items.into_iter().map(|item| api::lookup_item(&rates, &categories, item)).collect(),
+            })
+        }
+
+        // Re-read the rate file now instead of waiting for the next poll
+        (&Method::POST, "/admin/reload") => {
+            let reloaded = store.reload();
+            METRICS.record_reload(reloaded.is_ok());
+            match reloaded {
+                Ok(len) => {
+                    logging::info("rate table reloaded", serde_json::json!({"rates": len}));
+                    api::json_response(&serde_json::json!({"status": "ok", "rates": len}))
+                }
+                Err(e) => {
+                    logging::warn("rate table reload rejected", serde_json::json!({"error": e.to_string()}));
+                    Err(Problem::new(ErrorCode::InvalidRateTable, e.to_string()))
+                }
+            }
+        }
+
+        // Shut down gracefully, where there is no signal to do it with
+        (&Method::POST, "/admin/shutdown") => {
+            SHUTDOWN.request("admin");
+            let mut res = api::json_response(&serde_json::json!({
+                "status": "shutting_down",
+                "drain_ms": CONFIG.server.drain.as_millis() as u64,
+            }))?;
+            *res.status_mut() = StatusCode::ACCEPTED;
+            Ok(res)
         }
+
+        // Return the 404 Not Found for other routes.
+        _ => Err(Problem::not_found()),
     }
 }
 
+/// Whether a content negotiation header names JSON.
+fn accepts_json(req: &Request<Body>, header: HeaderName) -> bool {
+    req.headers().get(header)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("application/json"))
+}
+
+/// Returns the value of a query string parameter.
+fn query_param<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.uri().query()?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8001));
-    let make_svc = make_service_fn(|_| {
+    // Fail at startup if the configuration is invalid
+    lazy_static::initialize(&CONFIG);
+    logging::init("sales_tax_rate", CONFIG.log.level);
+    logging::info("configuration", serde_json::json!({"config": service_common::config::redacted(&*CONFIG)}));
+    trace::spawn_exporter(&CONFIG.tracing);
+
+    let store = Arc::new(RateStore::load(CONFIG.rates.file.clone())?);
+    let table = store.current();
+    logging::info("rate table loaded", serde_json::json!({
+        "rates": table.len(),
+        "file": store.path(),
+        "version": table.version(),
+    }));
+
+    let categories = Arc::new(Categories::load(
+        CONFIG.rates.product_file.as_deref(),
+        CONFIG.rates.category_rules_file.as_deref(),
+    )?);
+    logging::info("product categories loaded", serde_json::json!({
+        "products": categories.products(),
+        "rules": categories.rules(),
+    }));
+
+    // Poll the rate file for changes; a rejected file keeps the current table
+    let reload_secs = CONFIG.rates.reload_secs;
+    if store.path().is_some() && reload_secs > 0 {
+        let store = store.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(reload_secs));
+            loop {
+                interval.tick().await;
+                match store.reload_if_changed() {
+                    Ok(Some(len)) => {
+                        METRICS.record_reload(true);
+                        logging::info("rate table reloaded", serde_json::json!({"rates": len}));
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        METRICS.record_reload(false);
+                        logging::warn("rate table reload rejected", serde_json::json!({"error": e.to_string()}));
+                    }
+                }
+            }
+        });
+    }
+
+    let addr = CONFIG.server.addr();
+    let make_svc = make_service_fn(move |_| {
+        let store = store.clone();
+        let categories = categories.clone();
         async move {
             Ok::<_, Infallible>(service_fn(move |req| {
-                handle_request(req)
+                serve(req, store.clone(), categories.clone())
             }))
         }
     });
-    let server = Server::bind(&addr).serve(make_svc);
-    dbg!("Server started on port 8001");
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
-    }
+    let server = Server::bind(&addr).serve(make_svc).with_graceful_shutdown(SHUTDOWN.requested());
+    logging::info("server started", serde_json::json!({"addr": addr.to_string()}));
+
+    shutdown::run(server, CONFIG.server.drain).await;
     Ok(())
 }
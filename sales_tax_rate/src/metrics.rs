@@ -0,0 +1,74 @@
+//! Metrics in the Prometheus text format, served at `/metrics`: requests
+//! and their latency by route and status, lookups by zip code prefix, and
+//! the rate table in use.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use service_common::metrics::{Exposition, Requests};
+use crate::rates::RateTable;
+
+/// The routes requests are counted under. Any other path is counted as
+/// `other`, so that stray paths cannot grow the metrics without bound.
+const ROUTES: &[&str] = &["/", "/find_rate", "/find_rates", "/admin/reload", "/healthz", "/readyz", "/metrics", "/admin/shutdown"];
+
+/// The route a request for `path` is counted and logged under.
+pub fn route(path: &str) -> &'static str {
+    ROUTES.iter().find(|route| **route == path).copied().unwrap_or("other")
+}
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    requests: Requests,
+    /// Lookups by the first three digits of the zip code, and whether a
+    /// rate was on file.
+    lookups: Mutex<BTreeMap<(String, bool), u64>>,
+    reloads: AtomicU64,
+    rejected_reloads: AtomicU64,
+}
+
+impl Metrics {
+    /// Records a request answered in `elapsed`.
+    pub fn record_request(&self, method: &str, route: &'static str, status: u16, elapsed: Duration) {
+        self.requests.record(method, route, status, elapsed);
+    }
+
+    /// Records a lookup of a well-formed zip code.
+    pub fn record_lookup(&self, zip_prefix: &str, found: bool) {
+        *self.lookups.lock().unwrap().entry((zip_prefix.to_owned(), found)).or_default() += 1;
+    }
+
+    /// Records a reload of the rate table, or a rate file that was rejected.
+    pub fn record_reload(&self, accepted: bool) {
+        let counter = if accepted { &self.reloads } else { &self.rejected_reloads };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// All metrics, in the Prometheus text format.
+    pub fn render(&self, rates: &RateTable) -> String {
+        let mut out = Exposition::default();
+
+        self.requests.render(&mut out, "sales_tax_rate_http_request_duration_seconds");
+
+        out.header("sales_tax_rate_lookups_total", "counter", "Rate lookups by zip code prefix, as hits with a rate on file and misses without.");
+        for ((zip_prefix, found), count) in self.lookups.lock().unwrap().iter() {
+            let result = if *found { "hit" } else { "miss" };
+            out.sample("sales_tax_rate_lookups_total", &[("zip_prefix", zip_prefix), ("result", result)], count);
+        }
+
+        out.header("sales_tax_rate_table_rates", "gauge", "Rates in the rate table in use.");
+        out.sample("sales_tax_rate_table_rates", &[], rates.len());
+        out.header("sales_tax_rate_table_info", "gauge", "The version of the rate table in use.");
+        out.sample("sales_tax_rate_table_info", &[("version", rates.version())], 1);
+        out.header("sales_tax_rate_table_reloads_total", "counter", "Reloads of the rate file, by whether it was accepted.");
+        out.sample("sales_tax_rate_table_reloads_total", &[("result", "accepted")], self.reloads.load(Ordering::Relaxed));
+        out.sample("sales_tax_rate_table_reloads_total", &[("result", "rejected")], self.rejected_reloads.load(Ordering::Relaxed));
+
+        out.into_text()
+    }
+}
@@ -0,0 +1,147 @@
+//! The ways a request to `sales_tax_rate` can fail. Each is answered as an
+//! RFC 7807 problem with a stable `code` and the API version, or as plain
+//! text to callers of the original plain-text contract.
+
+use std::fmt::Display;
+use hyper::{Body, Response, StatusCode};
+use serde::Serialize;
+use service_common::{logging, problem};
+use crate::api::API_VERSION;
+
+/// The stable, machine-readable reason a request failed.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidRequest,
+    UnsupportedApiVersion,
+    BatchTooLarge,
+    InvalidZip,
+    InvalidDate,
+    /// A category no product or category rule names.
+    InvalidCategory,
+    RateNotFound,
+    /// A reloaded rate table failed validation.
+    InvalidRateTable,
+    /// An admin route was called without the admin token, or admin routes
+    /// are off.
+    Unauthorized,
+    /// No such route.
+    NotFound,
+    /// Anything else that went wrong on our side.
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest
+            | ErrorCode::UnsupportedApiVersion
+            | ErrorCode::InvalidZip
+            | ErrorCode::InvalidDate
+            | ErrorCode::InvalidCategory => StatusCode::BAD_REQUEST,
+            ErrorCode::BatchTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::RateNotFound | ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::InvalidRateTable => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// A failed request, answered as a problem.
+#[derive(Debug)]
+pub struct Problem {
+    pub code: ErrorCode,
+    pub detail: String,
+    /// Whether to answer with plain text, for callers of the original
+    /// plain-text contract of `/find_rate`.
+    pub plain_text: bool,
+}
+
+/// The members a lookup's problems have beyond the shared ones.
+#[derive(Serialize)]
+struct Extra {
+    api_version: u32,
+}
+
+impl Problem {
+    pub fn new(code: ErrorCode, detail: impl Into<String>) -> Self {
+        Problem { code, detail: detail.into(), plain_text: false }
+    }
+
+    pub fn not_found() -> Self {
+        Problem::new(ErrorCode::NotFound, "There is nothing at this path")
+    }
+
+    pub fn unauthorized() -> Self {
+        Problem::new(ErrorCode::Unauthorized, "Admin routes need the configured admin token as a bearer token")
+    }
+
+    /// An internal failure. The cause is logged rather than sent, so that
+    /// nothing about our internals leaks to the client.
+    pub fn internal(cause: impl Display) -> Self {
+        logging::error("internal error", serde_json::json!({"cause": cause.to_string()}));
+        Problem::new(ErrorCode::Internal, "The request could not be handled")
+    }
+
+    /// Answers with plain text unless `json` was negotiated.
+    pub fn negotiated(self, json: bool) -> Self {
+        Problem { plain_text: !json, ..self }
+    }
+
+    /// The problem as a response, or as the detail followed by the request
+    /// ID in plain text.
+    pub fn response(&self, correlation_id: &str) -> Response<Body> {
+        let status = self.code.status();
+        if !self.plain_text {
+            return problem::response(status, self.code, &self.detail, correlation_id, Extra { api_version: API_VERSION });
+        }
+        let mut res = Response::new(Body::from(format!("{} (request id: {})", self.detail, correlation_id)));
+        *res.status_mut() = status;
+        res
+    }
+}
+
+impl From<anyhow::Error> for Problem {
+    fn from(e: anyhow::Error) -> Self {
+        Problem::internal(e)
+    }
+}
+
+impl From<hyper::Error> for Problem {
+    fn from(e: hyper::Error) -> Self {
+        Problem::internal(e)
+    }
+}
+
+impl From<serde_json::Error> for Problem {
+    fn from(e: serde_json::Error) -> Self {
+        Problem::internal(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body(res: Response<Body>) -> String {
+        String::from_utf8(hyper::body::to_bytes(res).await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_plain_text_problem_carries_the_request_id() {
+        let res = Problem::new(ErrorCode::InvalidZip, "Invalid zip code").negotiated(false).response("abc-123");
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body(res).await, "Invalid zip code (request id: abc-123)");
+    }
+
+    #[tokio::test]
+    async fn a_json_problem_carries_the_request_id() {
+        let res = Problem::new(ErrorCode::RateNotFound, "No rate").negotiated(true).response("abc-123");
+        let problem: serde_json::Value = serde_json::from_str(&body(res).await).unwrap();
+        assert_eq!(problem["code"], "rate_not_found");
+        assert_eq!(problem["correlation_id"], "abc-123");
+        assert_eq!(problem["request_id"], "abc-123");
+        assert_eq!(problem["api_version"], API_VERSION);
+    }
+}
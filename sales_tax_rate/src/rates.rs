@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, NaiveDate, Utc};
+use csv::Reader;
+use serde::{Deserialize, Serialize};
+use crate::taxability::Taxability;
+use crate::zip::ZipCode;
+
+/// The table compiled into the binary, used when no rate file is configured.
+const EMBEDDED_RATES: &[u8] = include_bytes!("rates_by_zipcode.csv");
+
+/// The level of government that levies a component of the sales tax.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JurisdictionKind {
+    State,
+    County,
+    City,
+    SpecialDistrict,
+}
+
+/// One component of the combined sales tax rate for a zip code.
+#[derive(Serialize, Clone, Debug)]
+pub struct Jurisdiction {
+    #[serde(rename = "type")]
+    pub kind: JurisdictionKind,
+    pub name: String,
+    pub code: String,
+    pub rate: f64,
+    /// How the jurisdiction taxes the category looked up, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub taxability: Option<Taxability>,
+}
+
+/// The combined rate for a zip code and the jurisdictions it is made of,
+/// in force from `effective_from` through `effective_to` inclusive. An
+/// open `effective_to` means the rate applies until further notice.
+#[derive(Serialize, Clone, Debug)]
+pub struct ZipRate {
+    /// The zip code the rate is on file for, which is the 5 digit zip when
+    /// a ZIP+4 lookup fell back to it.
+    pub matched_zip: String,
+    pub rate: f64,
+    pub jurisdictions: Vec<Jurisdiction>,
+    pub effective_from: NaiveDate,
+    pub effective_to: Option<NaiveDate>,
+}
+
+impl ZipRate {
+    fn applies_on(&self, date: NaiveDate) -> bool {
+        self.effective_from <= date && self.effective_to.iter().all(|&to| date <= to)
+    }
+}
+
+/// A row of the rate table CSV. Each jurisdiction is a name, code and rate
+/// column group; a zip code outside a county, city or special district
+/// leaves that group empty.
+#[derive(Deserialize)]
+struct RateRow {
+    zip: String,
+    effective_from: NaiveDate,
+    effective_to: Option<NaiveDate>,
+    state: Option<String>,
+    state_code: Option<String>,
+    state_rate: Option<f64>,
+    county: Option<String>,
+    county_code: Option<String>,
+    county_rate: Option<f64>,
+    city: Option<String>,
+    city_code: Option<String>,
+    city_rate: Option<f64>,
+    special_district: Option<String>,
+    special_district_code: Option<String>,
+    special_district_rate: Option<f64>,
+}
+
+/// The sales tax rate table, parsed and validated once and indexed by zip
+/// code so a lookup costs the same no matter how many rates are loaded.
+/// Each zip code maps to its rates ordered by effective date.
+pub struct RateTable {
+    rates: HashMap<ZipCode, Vec<ZipRate>>,
+    version: String,
+}
+
+impl RateTable {
+    /// Parses a CSV table with one row per zip code and effective date range
+    /// and a column group per jurisdiction. Any malformed row, out of range
+    /// rate, or overlapping or gapped date ranges for a zip code rejects the
+    /// whole table.
+    pub fn from_csv(data: &[u8]) -> Result<Self, anyhow::Error> {
+        let mut rdr = Reader::from_reader(data);
+        let headers = rdr.headers()?.clone();
+        let mut rates = HashMap::new();
+        for result in rdr.records() {
+            let record = result?;
+            let line = record.position().map_or(0, |p| p.line());
+            let row: RateRow = record.deserialize(Some(&headers))
+                .map_err(|e| anyhow!("line {}: {}", line, e))?;
+            let zip = ZipCode::parse(&row.zip)
+                .ok_or_else(|| anyhow!("line {}: invalid zip {:?}", line, row.zip))?;
+            if row.effective_to.is_some_and(|to| to < row.effective_from) {
+                bail!("line {}: zip {} is effective to before it is effective from", line, zip);
+            }
+
+            let groups = [
+                (JurisdictionKind::State, row.state, row.state_code, row.state_rate),
+                (JurisdictionKind::County, row.county, row.county_code, row.county_rate),
+                (JurisdictionKind::City, row.city, row.city_code, row.city_rate),
+                (JurisdictionKind::SpecialDistrict, row.special_district, row.special_district_code, row.special_district_rate),
+            ];
+            let mut jurisdictions = Vec::new();
+            for (kind, name, code, rate) in groups {
+                match (name, code, rate) {
+                    (None, None, None) => {}
+                    (Some(name), Some(code), Some(rate)) => {
+                        if !(0.0..=1.0).contains(&rate) {
+                            bail!("line {}: {:?} rate {} for zip {} is out of range", line, kind, rate, zip);
+                        }
+                        jurisdictions.push(Jurisdiction { kind, name, code, rate, taxability: None });
+                    }
+                    _ => bail!("line {}: {:?} for zip {} needs a name, code and rate", line, kind, zip),
+                }
+            }
+            if !jurisdictions.iter().any(|j| j.kind == JurisdictionKind::State) {
+                bail!("line {}: zip {} has no state rate", line, zip);
+            }
+
+            let rate = combined_rate(&jurisdictions);
+            if rate > 1.0 {
+                bail!("line {}: combined rate {} for zip {} is out of range", line, rate, zip);
+            }
+            rates.entry(zip.clone()).or_insert_with(Vec::new).push(ZipRate {
+                matched_zip: zip.to_string(),
+                rate,
+                jurisdictions,
+                effective_from: row.effective_from,
+                effective_to: row.effective_to,
+            });
+        }
+
+        if rates.is_empty() {
+            bail!("rate table is empty");
+        }
+        for (zip, dated) in rates.iter_mut() {
+            dated.sort_by_key(|r| r.effective_from);
+            check_continuous(zip, dated)?;
+        }
+        Ok(RateTable { rates, version: table_version(data) })
+    }
+
+    /// Returns the rate in force on `date` for a zip code. A ZIP+4 code
+    /// falls back to its 5 digit zip when it has no rate of its own.
+    pub fn find(&self, zip: &ZipCode, date: NaiveDate) -> Option<&ZipRate> {
+        if zip.has_plus4() {
+            if let Some(rate) = self.find_exact(zip, date) {
+                return Some(rate);
+            }
+        }
+        self.find_exact(&zip.zip5(), date)
+    }
+
+    fn find_exact(&self, zip: &ZipCode, date: NaiveDate) -> Option<&ZipRate> {
+        let dated = self.rates.get(zip)?;
+        let next = dated.partition_point(|r| r.effective_from <= date);
+        dated[..next].last().filter(|r| r.applies_on(date))
+    }
+
+    pub fn len(&self) -> usize {
+        self.rates.len()
+    }
+
+    /// A fingerprint of the table contents, the same on every replica that
+    /// loaded the same file.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+}
+
+/// Checks that the date ranges of a zip code's rates, sorted by start date,
+/// follow on from each other with no overlap and no gap.
+fn check_continuous(zip: &ZipCode, dated: &[ZipRate]) -> Result<(), anyhow::Error> {
+    for pair in dated.windows(2) {
+        let (prev, next) = (&pair[0], &pair[1]);
+        let prev_to = match prev.effective_to {
+            Some(to) => to,
+            None => bail!("zip {}: open ended rate from {} overlaps rate from {}", zip, prev.effective_from, next.effective_from),
+        };
+        if next.effective_from <= prev_to {
+            bail!("zip {}: rate from {} overlaps rate to {}", zip, next.effective_from, prev_to);
+        }
+        if prev_to.succ_opt() != Some(next.effective_from) {
+            bail!("zip {}: no rate between {} and {}", zip, prev_to, next.effective_from);
+        }
+    }
+    Ok(())
+}
+
+/// Hashes the raw table with 64-bit FNV-1a.
+fn table_version(data: &[u8]) -> String {
+    let hash = data.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Today's date in UTC, the default date for rate lookups.
+pub fn today() -> NaiveDate {
+    DateTime::<Utc>::from(SystemTime::now()).date_naive()
+}
+
+/// Sums the component rates, rounded to a millionth so that float error in
+/// the sum never shows up in the published rate.
+pub fn combined_rate(jurisdictions: &[Jurisdiction]) -> f64 {
+    let sum: f64 = jurisdictions.iter().map(|j| j.rate).sum();
+    (sum * 1_000_000.0).round() / 1_000_000.0
+}
+
+/// Holds the active rate table and atomically swaps in a new one on reload.
+/// Requests take an `Arc` snapshot, so a reload never disturbs a lookup
+/// that is already in flight.
+pub struct RateStore {
+    path: Option<PathBuf>,
+    current: RwLock<Arc<RateTable>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl RateStore {
+    /// Loads the table from `path`, or from the embedded CSV when no path
+    /// is given.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, anyhow::Error> {
+        let (table, modified) = match &path {
+            Some(path) => read_file(path)?,
+            None => (RateTable::from_csv(EMBEDDED_RATES)?, None),
+        };
+        Ok(RateStore {
+            path,
+            current: RwLock::new(Arc::new(table)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    pub fn current(&self) -> Arc<RateTable> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn path(&self) -> Option<&PathBuf> {
+        self.path.as_ref()
+    }
+
+    /// Re-reads the rate file and swaps it in, returning the number of rates
+    /// loaded. A file that fails validation is rejected and the current table
+    /// stays active.
+    pub fn reload(&self) -> Result<usize, anyhow::Error> {
+        let path = self.path.as_ref().ok_or_else(|| anyhow!("no rate file configured"))?;
+        let (table, modified) = read_file(path)?;
+        let len = table.len();
+        *self.current.write().unwrap() = Arc::new(table);
+        *self.modified.lock().unwrap() = modified;
+        Ok(len)
+    }
+
+    /// Reloads the rate file if its modification time has changed since the
+    /// last load attempt, so a rejected file is not retried until it changes.
+    pub fn reload_if_changed(&self) -> Result<Option<usize>, anyhow::Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let modified = fs::metadata(path)?.modified().ok();
+        {
+            let mut last = self.modified.lock().unwrap();
+            if modified.is_some() && modified == *last {
+                return Ok(None);
+            }
+            *last = modified;
+        }
+        self.reload().map(Some)
+    }
+}
+
+fn read_file(path: &Path) -> Result<(RateTable, Option<SystemTime>), anyhow::Error> {
+    let modified = fs::metadata(path)
+        .map_err(|e| anyhow!("cannot read {}: {}", path.display(), e))?
+        .modified()
+        .ok();
+    let data = fs::read(path).map_err(|e| anyhow!("cannot read {}: {}", path.display(), e))?;
+    let table = RateTable::from_csv(&data).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    Ok((table, modified))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "zip,effective_from,effective_to,state,state_code,state_rate,county,county_code,county_rate,city,city_code,city_rate,special_district,special_district_code,special_district_rate\n";
+
+    /// A table of Texas state rates, one row per `(zip, from, to, rate)`.
+    fn table(rows: &[(&str, &str, &str, &str)]) -> Result<RateTable, anyhow::Error> {
+        let mut csv = HEADER.to_owned();
+        for (zip, from, to, rate) in rows {
+            csv.push_str(&format!("{},{},{},Texas,48,{},,,,,,,,,\n", zip, from, to, rate));
+        }
+        RateTable::from_csv(csv.as_bytes())
+    }
+
+    fn date(text: &str) -> NaiveDate {
+        text.parse().unwrap()
+    }
+
+    fn zip(text: &str) -> ZipCode {
+        ZipCode::parse(text).unwrap()
+    }
+
+    #[test]
+    fn continuous_ranges_are_accepted() {
+        let table = table(&[
+            ("78701", "2023-01-01", "2023-12-31", "0.0625"),
+            ("78701", "2024-01-01", "", "0.07"),
+        ]);
+        assert!(table.is_ok());
+    }
+
+    #[test]
+    fn overlapping_ranges_are_rejected() {
+        let error = table(&[
+            ("78701", "2023-01-01", "2023-12-31", "0.0625"),
+            ("78701", "2023-12-31", "", "0.07"),
+        ]).err().unwrap();
+        assert!(error.to_string().contains("overlaps"), "{}", error);
+    }
+
+    #[test]
+    fn gapped_ranges_are_rejected() {
+        let error = table(&[
+            ("78701", "2023-01-01", "2023-12-30", "0.0625"),
+            ("78701", "2024-01-01", "", "0.07"),
+        ]).err().unwrap();
+        assert!(error.to_string().contains("no rate between 2023-12-30 and 2024-01-01"), "{}", error);
+    }
+
+    #[test]
+    fn open_ended_range_followed_by_a_later_one_is_rejected() {
+        let error = table(&[
+            ("78701", "2023-01-01", "", "0.0625"),
+            ("78701", "2024-01-01", "", "0.07"),
+        ]).err().unwrap();
+        assert!(error.to_string().contains("open ended"), "{}", error);
+    }
+
+    #[test]
+    fn rows_are_checked_in_date_order_whatever_their_file_order() {
+        let table = table(&[
+            ("78701", "2024-01-01", "", "0.07"),
+            ("78701", "2023-01-01", "2023-12-31", "0.0625"),
+        ]).unwrap();
+        assert_eq!(table.find_exact(&zip("78701"), date("2023-06-01")).unwrap().rate, 0.0625);
+    }
+
+    #[test]
+    fn boundary_dates_fall_in_the_range_they_bound() {
+        let table = table(&[
+            ("78701", "2023-01-01", "2023-12-31", "0.0625"),
+            ("78701", "2024-01-01", "2024-06-30", "0.07"),
+        ]).unwrap();
+        let rate_on = |day| table.find_exact(&zip("78701"), date(day)).map(|rate| rate.rate);
+        assert_eq!(rate_on("2023-01-01"), Some(0.0625));
+        assert_eq!(rate_on("2023-12-31"), Some(0.0625));
+        assert_eq!(rate_on("2024-01-01"), Some(0.07));
+        assert_eq!(rate_on("2024-06-30"), Some(0.07));
+        assert_eq!(rate_on("2024-07-01"), None);
+    }
+
+    #[test]
+    fn dates_before_the_first_row_have_no_rate() {
+        let table = table(&[("78701", "2023-01-01", "", "0.0625")]).unwrap();
+        assert!(table.find_exact(&zip("78701"), date("2022-12-31")).is_none());
+        assert!(table.find(&zip("78701"), date("2022-12-31")).is_none());
+    }
+
+    #[test]
+    fn zip_plus4_falls_back_to_its_zip5() {
+        let table = table(&[
+            ("78701", "2023-01-01", "", "0.0625"),
+            ("78701-1234", "2023-01-01", "", "0.07"),
+        ]).unwrap();
+        assert_eq!(table.find(&zip("78701-1234"), date("2024-01-01")).unwrap().rate, 0.07);
+        let fallback = table.find(&zip("78701-9999"), date("2024-01-01")).unwrap();
+        assert_eq!((fallback.rate, fallback.matched_zip.as_str()), (0.0625, "78701"));
+    }
+}
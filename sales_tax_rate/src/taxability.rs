@@ -0,0 +1,259 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, bail};
+use csv::Reader;
+use serde::{Deserialize, Serialize};
+use crate::rates::{self, ZipRate};
+
+/// The mapping compiled into the binary from product ids to categories.
+const EMBEDDED_PRODUCTS: &[u8] = include_bytes!("product_categories.csv");
+
+/// The per-jurisdiction category rules compiled into the binary.
+const EMBEDDED_RULES: &[u8] = include_bytes!("category_rules.csv");
+
+/// How a jurisdiction taxes a product category.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Taxability {
+    Exempt,
+    Reduced,
+    Full,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct CategoryRule {
+    taxability: Taxability,
+    rate: f64,
+}
+
+#[derive(Deserialize)]
+struct ProductRow {
+    product_id: i64,
+    category: String,
+}
+
+#[derive(Deserialize)]
+struct RuleRow {
+    jurisdiction_code: String,
+    category: String,
+    taxability: Taxability,
+    rate: Option<f64>,
+}
+
+/// Product taxability categories and the rules each jurisdiction applies
+/// to them. A category a jurisdiction has no rule for is taxed at its full
+/// rate, as is a product with no category on file.
+pub struct Categories {
+    products: HashMap<i64, String>,
+    rules: HashMap<(String, String), CategoryRule>,
+    /// Every category a product or a rule names.
+    known: HashSet<String>,
+}
+
+impl Categories {
+    /// Loads the product mapping and category rules from the given files, or
+    /// from the embedded CSVs when no path is given.
+    pub fn load(products: Option<&Path>, rules: Option<&Path>) -> Result<Self, anyhow::Error> {
+        let products = match products {
+            Some(path) => parse_products(&read(path)?).map_err(|e| anyhow!("{}: {}", path.display(), e))?,
+            None => parse_products(EMBEDDED_PRODUCTS)?,
+        };
+        let rules = match rules {
+            Some(path) => parse_rules(&read(path)?).map_err(|e| anyhow!("{}: {}", path.display(), e))?,
+            None => parse_rules(EMBEDDED_RULES)?,
+        };
+        Ok(Categories::new(products, rules))
+    }
+
+    fn new(products: HashMap<i64, String>, rules: HashMap<(String, String), CategoryRule>) -> Self {
+        let known = products.values().chain(rules.keys().map(|(_, category)| category)).cloned().collect();
+        Categories { products, rules, known }
+    }
+
+    /// Resolves the category to tax under: the one asked for, otherwise the
+    /// product's category on file. A category asked for that no product or
+    /// rule names is most likely a typo, and is given back as the error
+    /// rather than taxed at the full rate.
+    pub fn resolve<'a>(&self, category: Option<&'a str>, product_id: Option<i64>) -> Result<Option<String>, &'a str> {
+        match category {
+            Some(category) if self.known.contains(category) => Ok(Some(category.to_owned())),
+            Some(category) => Err(category),
+            None => Ok(product_id.and_then(|id| self.products.get(&id)).cloned()),
+        }
+    }
+
+    /// Applies each jurisdiction's rule for `category` to a rate, returning
+    /// the rate with the exempt and reduced components adjusted and the
+    /// combined rate summed again.
+    pub fn apply(&self, rate: &ZipRate, category: &str) -> ZipRate {
+        let mut rate = rate.clone();
+        for jurisdiction in &mut rate.jurisdictions {
+            let rule = self.rules.get(&(jurisdiction.code.clone(), category.to_owned()));
+            let taxability = match rule {
+                Some(CategoryRule { taxability: Taxability::Exempt, .. }) => {
+                    jurisdiction.rate = 0.0;
+                    Taxability::Exempt
+                }
+                Some(CategoryRule { taxability: Taxability::Reduced, rate }) => {
+                    jurisdiction.rate = *rate;
+                    Taxability::Reduced
+                }
+                Some(CategoryRule { taxability: Taxability::Full, .. }) | None => Taxability::Full,
+            };
+            jurisdiction.taxability = Some(taxability);
+        }
+        rate.rate = rates::combined_rate(&rate.jurisdictions);
+        rate
+    }
+
+    pub fn products(&self) -> usize {
+        self.products.len()
+    }
+
+    pub fn rules(&self) -> usize {
+        self.rules.len()
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, anyhow::Error> {
+    fs::read(path).map_err(|e| anyhow!("cannot read {}: {}", path.display(), e))
+}
+
+fn parse_products(data: &[u8]) -> Result<HashMap<i64, String>, anyhow::Error> {
+    let mut rdr = Reader::from_reader(data);
+    let mut products = HashMap::new();
+    for result in rdr.deserialize() {
+        let row: ProductRow = result?;
+        let category = row.category.trim();
+        if category.is_empty() {
+            bail!("product {} has no category", row.product_id);
+        }
+        if products.insert(row.product_id, category.to_owned()).is_some() {
+            bail!("duplicate product {}", row.product_id);
+        }
+    }
+    Ok(products)
+}
+
+fn parse_rules(data: &[u8]) -> Result<HashMap<(String, String), CategoryRule>, anyhow::Error> {
+    let mut rdr = Reader::from_reader(data);
+    let mut rules = HashMap::new();
+    for result in rdr.deserialize() {
+        let row: RuleRow = result?;
+        let key = (row.jurisdiction_code.trim().to_owned(), row.category.trim().to_owned());
+        let rate = match (row.taxability, row.rate) {
+            (Taxability::Reduced, Some(rate)) if (0.0..=1.0).contains(&rate) => rate,
+            (Taxability::Reduced, _) => bail!("{:?} needs a reduced rate between 0 and 1", key),
+            (_, Some(_)) => bail!("{:?} only takes a rate when reduced", key),
+            (_, None) => 0.0,
+        };
+        let rule = CategoryRule { taxability: row.taxability, rate };
+        if rules.insert(key.clone(), rule).is_some() {
+            bail!("duplicate rule for {:?}", key);
+        }
+    }
+    Ok(rules)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use crate::rates::{Jurisdiction, JurisdictionKind};
+    use super::*;
+
+    const PRODUCTS: &str = "product_id,category\n321,general\n101,grocery\n105,digital\n";
+    const RULES: &str = "jurisdiction_code,category,taxability,rate\n\
+        48,grocery,exempt,\n\
+        4805000,grocery,exempt,\n\
+        48,digital,reduced,0.05\n\
+        4805000,digital,exempt,\n\
+        48,clothing,full,\n";
+
+    fn categories() -> Categories {
+        Categories::new(parse_products(PRODUCTS.as_bytes()).unwrap(), parse_rules(RULES.as_bytes()).unwrap())
+    }
+
+    fn jurisdiction(kind: JurisdictionKind, code: &str, rate: f64) -> Jurisdiction {
+        Jurisdiction { kind, name: code.to_owned(), code: code.to_owned(), rate, taxability: None }
+    }
+
+    /// Austin: the Texas state rate and a city rate.
+    fn austin() -> ZipRate {
+        let jurisdictions = vec![
+            jurisdiction(JurisdictionKind::State, "48", 0.0625),
+            jurisdiction(JurisdictionKind::City, "4805000", 0.02),
+        ];
+        ZipRate {
+            matched_zip: "78701".into(),
+            rate: rates::combined_rate(&jurisdictions),
+            jurisdictions,
+            effective_from: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+            effective_to: None,
+        }
+    }
+
+    fn taxed(rate: &ZipRate) -> Vec<(f64, Option<Taxability>)> {
+        rate.jurisdictions.iter().map(|jurisdiction| (jurisdiction.rate, jurisdiction.taxability)).collect()
+    }
+
+    #[test]
+    fn an_exempt_category_is_taxed_at_nothing() {
+        let rate = categories().apply(&austin(), "grocery");
+        assert_eq!(rate.rate, 0.0);
+        assert_eq!(taxed(&rate), [(0.0, Some(Taxability::Exempt)), (0.0, Some(Taxability::Exempt))]);
+    }
+
+    #[test]
+    fn each_jurisdiction_applies_its_own_rule() {
+        let rate = categories().apply(&austin(), "digital");
+        assert_eq!(taxed(&rate), [(0.05, Some(Taxability::Reduced)), (0.0, Some(Taxability::Exempt))]);
+        assert_eq!(rate.rate, 0.05);
+    }
+
+    #[test]
+    fn a_category_without_a_rule_is_taxed_at_the_full_rate() {
+        for category in ["general", "clothing"] {
+            let rate = categories().apply(&austin(), category);
+            assert_eq!(taxed(&rate), [(0.0625, Some(Taxability::Full)), (0.02, Some(Taxability::Full))]);
+            assert_eq!(rate.rate, 0.0825);
+        }
+    }
+
+    #[test]
+    fn the_category_on_file_is_used_unless_one_is_asked_for() {
+        let categories = categories();
+        assert_eq!(categories.resolve(None, Some(101)), Ok(Some("grocery".to_owned())));
+        assert_eq!(categories.resolve(Some("general"), Some(101)), Ok(Some("general".to_owned())));
+        assert_eq!(categories.resolve(None, Some(999)), Ok(None));
+        assert_eq!(categories.resolve(None, None), Ok(None));
+    }
+
+    #[test]
+    fn categories_named_only_by_a_rule_are_known() {
+        assert_eq!(categories().resolve(Some("clothing"), None), Ok(Some("clothing".to_owned())));
+    }
+
+    #[test]
+    fn an_unknown_category_is_rejected_rather_than_taxed_in_full() {
+        assert_eq!(categories().resolve(Some("grocerys"), Some(321)), Err("grocerys"));
+        assert_eq!(categories().resolve(Some(""), None), Err(""));
+    }
+
+    #[test]
+    fn malformed_tables_are_rejected() {
+        let rules = |row: &str| parse_rules(format!("jurisdiction_code,category,taxability,rate\n{}\n", row).as_bytes()).err();
+        assert!(rules("48,digital,reduced,").is_some());
+        assert!(rules("48,digital,reduced,1.5").is_some());
+        assert!(rules("48,grocery,exempt,0.01").is_some());
+        assert!(parse_rules(b"jurisdiction_code,category,taxability,rate\n48,grocery,exempt,\n48,grocery,full,\n").is_err());
+        assert!(parse_products(b"product_id,category\n1,general\n1,grocery\n").is_err());
+        assert!(parse_products(b"product_id,category\n1, \n").is_err());
+    }
+
+    #[test]
+    fn the_embedded_tables_load() {
+        let categories = Categories::load(None, None).unwrap();
+        assert!(categories.products() > 0 && categories.rules() > 0);
+    }
+}
@@ -0,0 +1,107 @@
+use std::fmt;
+
+/// A US zip code, with its ZIP+4 add-on when one was given.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ZipCode {
+    zip5: String,
+    plus4: Option<String>,
+}
+
+impl ZipCode {
+    /// Parses `78701`, `78701-1234` or `787011234`, ignoring surrounding
+    /// whitespace such as the trailing newline curl sends. Anything else is
+    /// not a zip code.
+    pub fn parse(input: &str) -> Option<ZipCode> {
+        let input = input.trim();
+        let (zip5, plus4) = match input.split_once('-') {
+            Some((zip5, plus4)) => (zip5, Some(plus4)),
+            None if is_digits(input, 9) => {
+                let (zip5, plus4) = input.split_at(5);
+                (zip5, Some(plus4))
+            }
+            None => (input, None),
+        };
+        if !is_digits(zip5, 5) || plus4.is_some_and(|plus4| !is_digits(plus4, 4)) {
+            return None;
+        }
+        Some(ZipCode {
+            zip5: zip5.to_owned(),
+            plus4: plus4.map(str::to_owned),
+        })
+    }
+
+    /// The 5 digit zip code without the add-on.
+    pub fn zip5(&self) -> ZipCode {
+        ZipCode { zip5: self.zip5.clone(), plus4: None }
+    }
+
+    /// The first three digits, which place a zip code in a region.
+    pub fn prefix(&self) -> &str {
+        &self.zip5[..3]
+    }
+
+    pub fn has_plus4(&self) -> bool {
+        self.plus4.is_some()
+    }
+}
+
+impl fmt::Display for ZipCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.plus4 {
+            Some(plus4) => write!(f, "{}-{}", self.zip5, plus4),
+            None => write!(f, "{}", self.zip5),
+        }
+    }
+}
+
+fn is_digits(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| b.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(input: &str) -> Option<String> {
+        ZipCode::parse(input).map(|zip| zip.to_string())
+    }
+
+    #[test]
+    fn five_digit_zip_codes_parse() {
+        assert_eq!(parsed("78701"), Some("78701".into()));
+        assert_eq!(parsed("00501"), Some("00501".into()));
+        assert!(!ZipCode::parse("78701").unwrap().has_plus4());
+    }
+
+    #[test]
+    fn zip_plus4_codes_parse_with_or_without_the_hyphen() {
+        assert_eq!(parsed("78701-1234"), Some("78701-1234".into()));
+        assert_eq!(parsed("787011234"), Some("78701-1234".into()));
+        assert_eq!(ZipCode::parse("787011234"), ZipCode::parse("78701-1234"));
+        assert!(ZipCode::parse("78701-1234").unwrap().has_plus4());
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_ignored() {
+        assert_eq!(parsed("78701\n"), Some("78701".into()));
+        assert_eq!(parsed("  78701-1234\r\n"), Some("78701-1234".into()));
+    }
+
+    #[test]
+    fn anything_else_is_not_a_zip_code() {
+        for input in [
+            "", "7870", "787012", "7870a", "78701-", "78701-123", "78701-12345", "78701-12a4",
+            "-1234", "78701 1234", "7870112345", "78701--1234", "７８７０１",
+        ] {
+            assert_eq!(parsed(input), None, "{input:?} parsed");
+        }
+    }
+
+    #[test]
+    fn the_zip5_and_prefix_drop_the_add_on() {
+        let zip = ZipCode::parse("78701-1234").unwrap();
+        assert_eq!(zip.zip5().to_string(), "78701");
+        assert!(!zip.zip5().has_plus4());
+        assert_eq!(zip.prefix(), "787");
+    }
+}
@@ -0,0 +1,284 @@
+//! The configuration layer both services are built on. Every setting has a
+//! default, which a TOML file may change, and an environment variable may
+//! change again. It is all checked at startup, so a bad setting stops the
+//! service instead of failing requests later.
+//!
+//! Each service declares its own `Config`, made of the sections here and
+//! its own, with the environment variables that override it.
+
+use anyhow::{anyhow, Context};
+use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION, VARY};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use toml::Value;
+
+/// Loads a configuration: the defaults, changed by the TOML file named by
+/// the `file_variable` environment variable, if set, then by the
+/// `overrides`, pairs of an environment variable and the key of the setting
+/// it overrides. `check` adds a problem for every setting that has the
+/// right type but a value that cannot work. Every problem is reported at
+/// once.
+pub fn load<C>(file_variable: &str, overrides: &[(&str, &str)], check: impl Fn(&C, &mut Vec<String>)) -> anyhow::Result<C>
+where
+    C: Serialize + DeserializeOwned + Default,
+{
+    let mut config = match std::env::var_os(file_variable) {
+        Some(path) => {
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("cannot read {}", path.to_string_lossy()))?;
+            toml::from_str(&text).with_context(|| format!("in {}", path.to_string_lossy()))?
+        }
+        None => C::default(),
+    };
+
+    let mut problems = Vec::new();
+    for (name, key) in overrides {
+        if let Ok(value) = std::env::var(name) {
+            match overridden(&config, key, &value) {
+                Ok(overridden) => config = overridden,
+                Err(e) => problems.push(format!("{}: {}", name, e)),
+            }
+        }
+    }
+    check(&config, &mut problems);
+    if problems.is_empty() {
+        Ok(config)
+    } else {
+        Err(anyhow!(problems.join("; ")))
+    }
+}
+
+/// The configuration with the setting at `key` changed to `value`, read as
+/// the same type as the setting.
+fn overridden<C: Serialize + DeserializeOwned>(config: &C, key: &str, value: &str) -> Result<C, String> {
+    let mut tree = Value::try_from(config).map_err(|e| e.to_string())?;
+    let (path, name) = key.rsplit_once('.').unwrap_or(("", key));
+    let table = path.split('.')
+        .filter(|part| !part.is_empty())
+        .try_fold(&mut tree, |tree, part| tree.get_mut(part))
+        .and_then(Value::as_table_mut)
+        .ok_or_else(|| format!("no setting {}", key))?;
+    let value = override_value(table.get(name), value)?;
+    table.insert(name.to_owned(), value);
+    tree.try_into().map_err(|e: toml::de::Error| e.message().to_owned())
+}
+
+/// Reads an environment variable's value as the type of the setting it
+/// overrides. A list is separated by commas, and a table is written as
+/// `KEY=VALUE` pairs.
+fn override_value(current: Option<&Value>, value: &str) -> Result<Value, String> {
+    let value = value.trim();
+    let list = || value.split(',').map(str::trim).filter(|item| !item.is_empty());
+    Ok(match current {
+        Some(Value::Integer(_)) => Value::Integer(value.parse().map_err(|_| format!("{:?} is not a whole number", value))?),
+        Some(Value::Float(_)) => Value::Float(value.parse().map_err(|_| format!("{:?} is not a number", value))?),
+        Some(Value::Boolean(_)) => Value::Boolean(value.parse().map_err(|_| format!("{:?} is not true or false", value))?),
+        Some(Value::Array(_)) => Value::Array(list().map(|item| Value::String(item.to_owned())).collect()),
+        Some(Value::Table(_)) => Value::Table(list()
+            .map(|entry| match entry.split_once('=') {
+                Some((key, value)) => Ok((key.trim().to_owned(), Value::String(value.trim().to_owned()))),
+                None => Err(format!("{:?} is not KEY=VALUE", entry)),
+            })
+            .collect::<Result<_, _>>()?),
+        _ => Value::String(value.to_owned()),
+    })
+}
+
+/// A configuration as a TOML tree, with passwords and other secrets masked
+/// so that it can be logged.
+pub fn redacted<C: Serialize>(config: &C) -> Value {
+    let mut tree = match Value::try_from(config) {
+        Ok(tree) => tree,
+        Err(e) => return Value::String(format!("(cannot be shown: {})", e)),
+    };
+    redact(&mut tree);
+    tree
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::String(text) => *text = redact_url(text),
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                if ["password", "secret", "token"].iter().any(|secret| key.contains(secret)) {
+                    *value = Value::String("<redacted>".into());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// A URL with the password in it, if any, masked.
+pub fn redact_url(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_owned();
+    };
+    let authority = &rest[..rest.find('/').unwrap_or(rest.len())];
+    match authority.rfind('@').and_then(|at| Some((at, authority[..at].split_once(':')?.0))) {
+        Some((at, user)) => format!("{}://{}:****{}", scheme, user, &rest[at..]),
+        None => url.to_owned(),
+    }
+}
+
+/// Which web pages may call the service.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Origins such as `https://shop.example.com`, or `*` for any.
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig { allowed_origins: vec!["*".into()] }
+    }
+}
+
+/// The headers a cross-origin request may send. `Authorization` is left
+/// out, so that a browser cannot call the admin routes from another origin.
+const CORS_ALLOW_HEADERS: &str = "api,Keep-Alive,User-Agent,Content-Type,Accept,X-Correlation-Id,X-Request-Id,traceparent,tracestate";
+
+impl CorsConfig {
+    /// Adds the CORS headers to the answer to a request from `origin`.
+    pub fn add_headers(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        if let Some(origin) = self.allow_origin(origin) {
+            headers.insert("Access-Control-Allow-Origin", origin);
+        }
+        if !self.allows_any() {
+            headers.insert(VARY, HeaderValue::from_static("Origin"));
+        }
+        headers.insert("Access-Control-Allow-Methods", HeaderValue::from_static("GET, POST, OPTIONS"));
+        headers.insert("Access-Control-Allow-Headers", HeaderValue::from_static(CORS_ALLOW_HEADERS));
+        headers.insert("Access-Control-Expose-Headers", HeaderValue::from_static("X-Correlation-Id, X-Request-Id"));
+    }
+
+    /// The `Access-Control-Allow-Origin` to answer a request from `origin`
+    /// with, if that origin is allowed.
+    fn allow_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        if self.allows_any() {
+            return Some(HeaderValue::from_static("*"));
+        }
+        let origin = origin?;
+        let allowed = origin.to_str().is_ok_and(|origin| self.allowed_origins.iter().any(|allowed| allowed == origin));
+        allowed.then(|| origin.clone())
+    }
+
+    /// Whether any origin is allowed, so that the answer does not vary by
+    /// origin.
+    fn allows_any(&self) -> bool {
+        self.allowed_origins.iter().any(|allowed| allowed == "*")
+    }
+
+    pub fn check(&self, problems: &mut Vec<String>) {
+        for origin in &self.allowed_origins {
+            if origin != "*" && !origin.starts_with("http://") && !origin.starts_with("https://") {
+                problems.push(format!("cors.allowed_origins must be http(s) origins or *, not {:?}", origin));
+            }
+        }
+    }
+}
+
+/// Who may use the `/admin/` routes.
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// The bearer token admin requests must carry. Without one, the admin
+    /// routes are off.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl AdminConfig {
+    /// Whether a request's `Authorization` header carries the admin token.
+    pub fn authorizes(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = &self.token else {
+            return false;
+        };
+        let given = headers.get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        // Compared in full whatever the first difference, so that timing
+        // does not give the token away
+        given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+
+    pub fn check(&self, problems: &mut Vec<String>) {
+        if self.token.as_ref().is_some_and(|token| token.len() < 16 || !token.bytes().all(|b| b.is_ascii_graphic())) {
+            problems.push("admin.token must be at least 16 printable characters, without spaces".into());
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// The least severe messages logged.
+    pub level: Level,
+}
+
+/// (De)serializes a duration as whole milliseconds.
+pub mod millis {
+    use std::time::Duration;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(allowed_origins: &[&str], origin: Option<&'static str>) -> HeaderMap {
+        let cors = CorsConfig { allowed_origins: allowed_origins.iter().map(|origin| origin.to_string()).collect() };
+        let mut headers = HeaderMap::new();
+        cors.add_headers(origin.map(HeaderValue::from_static).as_ref(), &mut headers);
+        headers
+    }
+
+    #[test]
+    fn any_origin_is_allowed_by_default() {
+        let headers = cors(&["*"], Some("https://shop.example.com"));
+        assert_eq!(headers["access-control-allow-origin"], "*");
+        assert!(headers.get(VARY).is_none());
+    }
+
+    #[test]
+    fn only_listed_origins_are_allowed() {
+        let allowed = ["https://shop.example.com"];
+        assert_eq!(cors(&allowed, Some("https://shop.example.com"))["access-control-allow-origin"], "https://shop.example.com");
+        assert!(cors(&allowed, Some("https://evil.example.com")).get("access-control-allow-origin").is_none());
+        assert!(cors(&allowed, None).get("access-control-allow-origin").is_none());
+        assert_eq!(cors(&allowed, None)[VARY], "Origin");
+    }
+
+    #[test]
+    fn the_allowed_headers_include_accept_but_not_authorization() {
+        let headers = cors(&["*"], None);
+        let allowed = headers["access-control-allow-headers"].to_str().unwrap().to_ascii_lowercase();
+        let allowed: Vec<&str> = allowed.split(',').collect();
+        assert!(allowed.contains(&"accept") && allowed.contains(&"content-type") && allowed.contains(&"traceparent"));
+        assert!(!allowed.contains(&"authorization"));
+        assert_eq!(headers["access-control-expose-headers"], "X-Correlation-Id, X-Request-Id");
+    }
+}
@@ -0,0 +1,14 @@
+//! What `sales_tax_rate` and `order_total` share: how they are configured,
+//! log, trace, serve metrics, answer errors and shut down. Each service
+//! keeps its own settings, routes, metrics and error codes, and names
+//! itself at startup.
+
+#[macro_use]
+extern crate lazy_static;
+
+pub mod config;
+pub mod logging;
+pub mod metrics;
+pub mod problem;
+pub mod shutdown;
+pub mod trace;
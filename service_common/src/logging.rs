@@ -0,0 +1,84 @@
+//! Structured logs: one JSON object per line, with a timestamp, level,
+//! service, message and fields such as the request's correlation ID. Fields
+//! that may hold personal data are redacted before anything is written.
+
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{Map, Value};
+use crate::config::Level;
+
+/// Fields that may hold personal data. Their values are never logged.
+pub const PII_FIELDS: &[&str] = &["shipping_address", "email", "phone", "customer_name"];
+
+/// The service every line is logged for, and the least severe level
+/// logged.
+static SETUP: OnceLock<(&str, Level)> = OnceLock::new();
+
+/// Names the service logging, and sets the least severe level logged. Until
+/// then, lines carry no service and the level is `info`.
+pub fn init(service: &'static str, level: Level) {
+    let _ = SETUP.set((service, level));
+}
+
+/// The service logging, as named by `init`.
+pub fn service() -> &'static str {
+    SETUP.get().map_or("", |(service, _)| service)
+}
+
+/// Logs a message, with `fields` given as a JSON object, if the configured
+/// level lets it through. Warnings and errors go to stderr, everything
+/// else to stdout.
+pub fn log(level: Level, message: &str, fields: Value) {
+    if level > SETUP.get().map_or(Level::default(), |(_, level)| *level) {
+        return;
+    }
+    let mut line = Map::new();
+    line.insert("ts".into(), DateTime::<Utc>::from(SystemTime::now()).to_rfc3339_opts(SecondsFormat::Millis, true).into());
+    line.insert("level".into(), serde_json::to_value(level).unwrap_or_default());
+    line.insert("service".into(), service().into());
+    line.insert("msg".into(), message.into());
+    if let Value::Object(fields) = redacted(fields) {
+        line.extend(fields);
+    }
+    let line = Value::Object(line);
+    if level <= Level::Warn {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+pub fn error(message: &str, fields: Value) {
+    log(Level::Error, message, fields);
+}
+
+pub fn warn(message: &str, fields: Value) {
+    log(Level::Warn, message, fields);
+}
+
+pub fn info(message: &str, fields: Value) {
+    log(Level::Info, message, fields);
+}
+
+pub fn debug(message: &str, fields: Value) {
+    log(Level::Debug, message, fields);
+}
+
+fn redacted(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(fields.into_iter()
+            .map(|(name, value)| {
+                let value = if PII_FIELDS.contains(&name.as_str()) { Value::from("[redacted]") } else { redacted(value) };
+                (name, value)
+            })
+            .collect()),
+        Value::Array(items) => Value::Array(items.into_iter().map(redacted).collect()),
+        value => value,
+    }
+}
+
+/// A duration in milliseconds, to three decimal places, for log fields.
+pub fn millis(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
@@ -0,0 +1,164 @@
+//! Writing metrics in the Prometheus text format, and the request latency
+//! histograms every service serves at `/metrics`. Each service keeps its
+//! own metric sets and names.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The upper bounds of the latency histograms' buckets, in seconds.
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// The methods requests are counted under. Any other method is counted as
+/// `other`, since a client may send any token as a method.
+const METHODS: &[&str] = &["GET", "POST", "OPTIONS"];
+
+/// The method a request is counted under.
+pub fn method(method: &str) -> &'static str {
+    METHODS.iter().find(|known| **known == method).copied().unwrap_or("other")
+}
+
+/// A latency histogram.
+#[derive(Default)]
+pub struct Histogram {
+    /// How many observations fell in each bucket, not counting the ones in
+    /// the buckets below it.
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+/// Requests answered, by route, method and status.
+#[derive(Default)]
+pub struct Requests(Mutex<BTreeMap<(&'static str, &'static str, u16), Histogram>>);
+
+impl Requests {
+    /// Records a request answered in `elapsed`.
+    pub fn record(&self, method: &str, route: &'static str, status: u16, elapsed: Duration) {
+        self.0.lock().unwrap()
+            .entry((route, self::method(method), status))
+            .or_default()
+            .observe(elapsed);
+    }
+
+    /// Writes the requests as the histogram `name`.
+    pub fn render(&self, out: &mut Exposition, name: &str) {
+        out.header(name, "histogram", "Requests answered, by route, method and status.");
+        for ((route, method, status), histogram) in self.0.lock().unwrap().iter() {
+            let status = status.to_string();
+            out.histogram(name, &[("route", route), ("method", method), ("status", &status)], histogram);
+        }
+    }
+}
+
+/// Writes metrics in the Prometheus text format.
+#[derive(Default)]
+pub struct Exposition(String);
+
+impl Exposition {
+    pub fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let _ = writeln!(self.0, "{}{} {}", name, format_labels(labels), value);
+    }
+
+    pub fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let le = bound.to_string();
+            self.sample(&format!("{}_bucket", name), &[labels, &[("le", &le)]].concat(), cumulative);
+        }
+        self.sample(&format!("{}_bucket", name), &[labels, &[("le", "+Inf")]].concat(), histogram.count);
+        self.sample(&format!("{}_sum", name), labels, histogram.sum);
+        self.sample(&format!("{}_count", name), labels, histogram.count);
+    }
+
+    /// The metrics written, as served.
+    pub fn into_text(self) -> String {
+        self.0
+    }
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels.iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn methods_other_than_the_served_ones_are_counted_as_other() {
+        assert_eq!(method("GET"), "GET");
+        assert_eq!(method("POST"), "POST");
+        assert_eq!(method("OPTIONS"), "OPTIONS");
+        assert_eq!(method("PURGE"), "other");
+        assert_eq!(method("get"), "other");
+    }
+
+    #[test]
+    fn requests_with_made_up_methods_share_one_series() {
+        let requests = Requests::default();
+        for method in ["FOO", "BAR", "BAZ"] {
+            requests.record(method, "/", 404, Duration::from_millis(1));
+        }
+        let mut out = Exposition::default();
+        requests.render(&mut out, "requests");
+        let text = out.into_text();
+        assert!(text.contains("requests_count{route=\"/\",method=\"other\",status=\"404\"} 3"), "{}", text);
+        assert!(!text.contains("FOO"));
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::default();
+        for millis in [1, 7, 7, 20_000] {
+            histogram.observe(Duration::from_millis(millis));
+        }
+        let mut out = Exposition::default();
+        out.histogram("latency", &[], &histogram);
+        let text = out.into_text();
+        assert!(text.contains("latency_bucket{le=\"0.005\"} 1\n"));
+        assert!(text.contains("latency_bucket{le=\"0.01\"} 3\n"));
+        assert!(text.contains("latency_bucket{le=\"10\"} 3\n"));
+        assert!(text.contains("latency_bucket{le=\"+Inf\"} 4\n"));
+        assert!(text.contains("latency_count 4\n"));
+        assert_eq!(histogram.count(), 4);
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(format_labels(&[]), "");
+        assert_eq!(format_labels(&[("a", "x\"y\\z\n")]), "{a=\"x\\\"y\\\\z\\n\"}");
+        assert_eq!(format_labels(&[("a", "1"), ("b", "2")]), "{a=\"1\",b=\"2\"}");
+    }
+}
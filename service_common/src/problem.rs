@@ -0,0 +1,72 @@
+//! Errors as RFC 7807 `application/problem+json` responses. Every error
+//! carries a stable machine-readable `code` and the request's correlation
+//! ID, so a failure seen by a client can be found in the logs. Each service
+//! has its own codes, and may add members of its own.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use hyper::header::{HeaderValue, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Serialize;
+
+/// The header a correlation ID is read from and echoed in.
+pub const CORRELATION_ID: &str = "x-correlation-id";
+/// The header many proxies and clients carry the same ID in. It is read
+/// when there is no `X-Correlation-Id`, and echoed alongside it.
+pub const REQUEST_ID: &str = "x-request-id";
+
+#[derive(Serialize)]
+struct ProblemBody<'a, C, E> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: &'a str,
+    code: C,
+    correlation_id: &'a str,
+    request_id: &'a str,
+    #[serde(flatten)]
+    extra: E,
+}
+
+/// A problem as a response, with `extra` as members of its own. Codes are
+/// documented by name, so the type is `about:blank` and the title is the
+/// status's reason phrase.
+pub fn response<C: Serialize, E: Serialize>(status: StatusCode, code: C, detail: &str, correlation_id: &str, extra: E) -> Response<Body> {
+    let body = ProblemBody {
+        kind: "about:blank",
+        title: status.canonical_reason().unwrap_or("Error"),
+        status: status.as_u16(),
+        detail,
+        code,
+        correlation_id,
+        request_id: correlation_id,
+        extra,
+    };
+    let mut res = Response::new(Body::from(serde_json::to_string(&body).unwrap_or_default()));
+    *res.status_mut() = status;
+    if status == StatusCode::UNAUTHORIZED {
+        res.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    }
+    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/problem+json"));
+    res
+}
+
+/// The request's correlation ID: the caller's own, from `X-Correlation-Id`
+/// or else `X-Request-Id`, when it sent a usable one, otherwise a new one.
+pub fn correlation_id(req: &Request<Body>) -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let given = [CORRELATION_ID, REQUEST_ID].iter().find_map(|name| {
+        req.headers().get(*name)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic()))
+    });
+    match given {
+        Some(id) => id.to_owned(),
+        None => {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64);
+            format!("{:016x}{:04x}", nanos, NEXT.fetch_add(1, Ordering::Relaxed) & 0xffff)
+        }
+    }
+}
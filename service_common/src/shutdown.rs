@@ -0,0 +1,121 @@
+//! Graceful shutdown. On SIGTERM or Ctrl-C, or on `POST /admin/shutdown`
+//! where there are no signals (as under WASI), the server stops accepting
+//! connections and reports itself not ready, and requests in flight get
+//! until `server.drain_ms` has passed to finish before the service exits.
+
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::watch;
+use crate::{logging, trace};
+
+lazy_static! {
+    pub static ref SHUTDOWN: Shutdown = Shutdown::new();
+}
+
+pub struct Shutdown {
+    requested: watch::Sender<bool>,
+    in_flight: AtomicUsize,
+    /// How long requests in flight get to finish, as given to `run`.
+    drain: OnceLock<Duration>,
+}
+
+impl Shutdown {
+    fn new() -> Self {
+        let (requested, _) = watch::channel(false);
+        Shutdown { requested, in_flight: AtomicUsize::new(0), drain: OnceLock::new() }
+    }
+
+    /// Starts shutting down, unless it already has.
+    pub fn request(&self, reason: &str) {
+        if !self.requested.send_replace(true) {
+            logging::info("shutting down", serde_json::json!({
+                "reason": reason,
+                "in_flight": self.in_flight(),
+                "drain_ms": self.drain.get().map(|drain| drain.as_millis() as u64),
+            }));
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.requested.borrow()
+    }
+
+    /// Waits until shutting down starts.
+    pub async fn requested(&self) {
+        let mut requested = self.requested.subscribe();
+        while !*requested.borrow_and_update() {
+            if requested.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped.
+    pub fn begin(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight { shutdown: self }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+}
+
+/// A request being answered.
+pub struct InFlight<'a> {
+    shutdown: &'a Shutdown,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.shutdown.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Runs `server` until it has drained, or until `drain` has passed since
+/// shutting down started, whichever is first, then exports the last spans.
+/// Once shutting down starts no connections are accepted.
+pub async fn run<E: Display>(server: impl Future<Output = Result<(), E>>, drain: Duration) {
+    let _ = SHUTDOWN.drain.set(drain);
+    on_signals();
+    let deadline = async {
+        SHUTDOWN.requested().await;
+        tokio::time::sleep(drain).await;
+    };
+    tokio::select! {
+        result = server => match result {
+            Ok(()) => logging::info("drained", serde_json::json!({})),
+            Err(e) => logging::error("server error", serde_json::json!({"error": e.to_string()})),
+        },
+        _ = deadline => logging::warn("drain deadline passed", serde_json::json!({"cut_off": SHUTDOWN.in_flight()})),
+    }
+    trace::flush().await;
+    logging::info("stopped", serde_json::json!({}));
+}
+
+/// Shuts down on SIGTERM, as sent by container runtimes, or Ctrl-C.
+#[cfg(unix)]
+fn on_signals() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async {
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                logging::warn("cannot listen for SIGTERM", serde_json::json!({"error": e.to_string()}));
+                return;
+            }
+        };
+        tokio::select! {
+            _ = terminate.recv() => SHUTDOWN.request("SIGTERM"),
+            _ = tokio::signal::ctrl_c() => SHUTDOWN.request("SIGINT"),
+        }
+    });
+}
+
+/// WASI has no signals, so only `POST /admin/shutdown` shuts down.
+#[cfg(not(unix))]
+fn on_signals() {}
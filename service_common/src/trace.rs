@@ -0,0 +1,411 @@
+//! Distributed tracing with W3C Trace Context. A request's `traceparent`
+//! and `tracestate` headers are continued, or a new trace is started, and
+//! the request and each call it makes to another service are recorded as
+//! spans. Spans are exported in batches as OTLP/JSON, to a collector over
+//! HTTP or to a file.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use hyper::header::{HeaderMap, CONTENT_TYPE};
+use hyper::{Body, Client, Method, Request};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::config;
+use crate::logging;
+
+/// The header a trace context is read from and passed on in.
+pub const TRACEPARENT: &str = "traceparent";
+/// The header vendor-specific trace state is passed on in, unchanged.
+pub const TRACESTATE: &str = "tracestate";
+
+/// The most spans waiting to be exported. Spans ended while the queue is
+/// full are dropped, so that a collector that is down cannot use up memory.
+const MAX_QUEUED: usize = 10000;
+
+/// How long an export to a collector may take.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where spans go.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Exporter {
+    /// Nowhere: trace context is still passed on, but no spans are kept.
+    #[default]
+    None,
+    /// Appended to `file`, one OTLP/JSON export request per line.
+    File,
+    /// Posted as OTLP/JSON to a collector at `otlp_endpoint`.
+    Otlp,
+}
+
+/// How spans are exported.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingSettings {
+    pub exporter: Exporter,
+    /// The file the `file` exporter appends to.
+    pub file: PathBuf,
+    /// The OTLP/HTTP traces URL of the collector the `otlp` exporter posts to.
+    pub otlp_endpoint: String,
+    /// How often waiting spans are exported.
+    #[serde(rename = "flush_ms", with = "config::millis")]
+    pub flush: Duration,
+}
+
+impl Default for TracingSettings {
+    fn default() -> Self {
+        TracingSettings {
+            exporter: Exporter::None,
+            file: PathBuf::from("spans.jsonl"),
+            otlp_endpoint: "http://localhost:4318/v1/traces".into(),
+            flush: Duration::from_millis(1000),
+        }
+    }
+}
+
+impl TracingSettings {
+    pub fn check(&self, problems: &mut Vec<String>) {
+        let collector = self.otlp_endpoint.parse::<hyper::Uri>();
+        if !collector.is_ok_and(|uri| uri.scheme_str() == Some("http") && uri.host().is_some()) {
+            problems.push(format!("tracing.otlp_endpoint must be an http URL, not {:?}", config::redact_url(&self.otlp_endpoint)));
+        }
+        if self.flush.is_zero() {
+            problems.push("tracing.flush_ms must not be 0".into());
+        }
+    }
+}
+
+/// The settings spans are exported with, as given to `spawn_exporter`.
+/// Until then, spans are not kept.
+static SETTINGS: OnceLock<&TracingSettings> = OnceLock::new();
+
+lazy_static! {
+    static ref QUEUE: Mutex<Vec<Span>> = Mutex::new(Vec::new());
+}
+
+/// Where a span sits in a trace: the IDs passed on in `traceparent`, and
+/// the caller's `tracestate`.
+#[derive(Clone, Debug)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+    /// Whether the trace is being recorded, as decided where it started.
+    pub sampled: bool,
+    pub state: Option<String>,
+}
+
+impl TraceContext {
+    /// The trace context a request's headers carry, if they carry a valid
+    /// one.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let traceparent = headers.get(TRACEPARENT)?.to_str().ok()?;
+        let mut parts = traceparent.trim().split('-');
+        let version = parts.next().filter(|part| is_hex(part, 2) && *part != "ff")?;
+        let trace_id = parts.next().filter(|part| is_hex(part, 32))?;
+        let span_id = parts.next().filter(|part| is_hex(part, 16))?;
+        let flags = parts.next().filter(|part| is_hex(part, 2))?;
+        // Later versions may add fields, but version 00 has exactly four
+        if version == "00" && parts.next().is_some() {
+            return None;
+        }
+        let trace_id = u128::from_str_radix(trace_id, 16).ok().filter(|id| *id != 0)?;
+        let span_id = u64::from_str_radix(span_id, 16).ok().filter(|id| *id != 0)?;
+        let sampled = u8::from_str_radix(flags, 16).ok()? & 1 == 1;
+
+        // Several tracestate headers make up one list
+        let state: Vec<&str> = headers.get_all(TRACESTATE).iter().filter_map(|value| value.to_str().ok()).collect();
+        let state = Some(state.join(",")).filter(|state| !state.is_empty() && state.len() <= 512);
+        Some(TraceContext { trace_id, span_id, sampled, state })
+    }
+
+    /// The context as a `traceparent` header value.
+    pub fn traceparent(&self) -> String {
+        format!("00-{:032x}-{:016x}-{:02x}", self.trace_id, self.span_id, u8::from(self.sampled))
+    }
+
+    pub fn trace_id(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+}
+
+fn is_hex(part: &str, len: usize) -> bool {
+    part.len() == len && part.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SpanKind {
+    /// Answering a request.
+    Server,
+    /// Calling another service.
+    Client,
+}
+
+/// A timed operation in a trace. It is queued for export when it ends.
+pub struct Span {
+    context: TraceContext,
+    parent_id: Option<u64>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    started: Instant,
+    elapsed: Duration,
+    attributes: Vec<(&'static str, Value)>,
+    error: Option<String>,
+}
+
+impl Span {
+    /// Starts a span under `parent`, or at the root of a new trace.
+    pub fn start(name: impl Into<String>, kind: SpanKind, parent: Option<&TraceContext>) -> Span {
+        let context = match parent {
+            Some(parent) => TraceContext { span_id: new_span_id(), ..parent.clone() },
+            None => TraceContext {
+                trace_id: (u128::from(random_u64()) << 64) | u128::from(random_u64()),
+                span_id: new_span_id(),
+                sampled: true,
+                state: None,
+            },
+        };
+        Span {
+            context,
+            parent_id: parent.map(|parent| parent.span_id),
+            name: name.into(),
+            kind,
+            start: SystemTime::now(),
+            started: Instant::now(),
+            elapsed: Duration::ZERO,
+            attributes: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// The context to pass on to calls made within the span.
+    pub fn context(&self) -> &TraceContext {
+        &self.context
+    }
+
+    pub fn set(&mut self, key: &'static str, value: impl Into<Value>) {
+        self.attributes.push((key, value.into()));
+    }
+
+    /// Marks the span as failed.
+    pub fn fail(&mut self, message: impl Into<String>) {
+        self.error = Some(message.into());
+    }
+
+    /// Ends the span, and queues it for export if the trace is recorded.
+    pub fn end(mut self) {
+        let exported = SETTINGS.get().is_some_and(|settings| settings.exporter != Exporter::None);
+        if !self.context.sampled || !exported {
+            return;
+        }
+        self.elapsed = self.started.elapsed();
+        let mut queue = QUEUE.lock().unwrap();
+        if queue.len() < MAX_QUEUED {
+            queue.push(self);
+        }
+    }
+
+    fn to_otlp(&self) -> Value {
+        let end = self.start + self.elapsed;
+        let mut span = json!({
+            "traceId": self.context.trace_id(),
+            "spanId": format!("{:016x}", self.context.span_id),
+            "name": self.name,
+            "kind": match self.kind {
+                SpanKind::Server => 2,
+                SpanKind::Client => 3,
+            },
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(end),
+            "attributes": self.attributes.iter().map(|(key, value)| attribute(key, value)).collect::<Vec<_>>(),
+            "status": match &self.error {
+                Some(message) => json!({"code": 2, "message": message}),
+                None => json!({"code": 0}),
+            },
+        });
+        if let Some(parent_id) = self.parent_id {
+            span["parentSpanId"] = format!("{:016x}", parent_id).into();
+        }
+        if let Some(state) = &self.context.state {
+            span["traceState"] = state.as_str().into();
+        }
+        span
+    }
+}
+
+/// An attribute in OTLP/JSON, where a value is wrapped in its type.
+fn attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::Bool(value) => json!({"boolValue": value}),
+        Value::Number(number) if number.is_f64() => json!({"doubleValue": number}),
+        Value::Number(number) => json!({"intValue": number.to_string()}),
+        Value::String(text) => json!({"stringValue": text}),
+        other => json!({"stringValue": other.to_string()}),
+    };
+    json!({"key": key, "value": value})
+}
+
+/// A time as OTLP/JSON has it: nanoseconds since the epoch, as a string.
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos()).to_string()
+}
+
+fn new_span_id() -> u64 {
+    // Zero is not a valid span ID
+    loop {
+        let id = random_u64();
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+/// A random number from the clock and a counter, good enough to keep IDs
+/// from colliding and to spread retries out.
+pub fn random_u64() -> u64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64);
+    // splitmix64 over the clock and a counter
+    let mut x = nanos ^ STATE.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Exports waiting spans every `flush_ms`, unless spans are not exported.
+pub fn spawn_exporter(settings: &'static TracingSettings) {
+    if SETTINGS.set(settings).is_err() || settings.exporter == Exporter::None {
+        return;
+    }
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(settings.flush);
+        loop {
+            interval.tick().await;
+            flush().await;
+        }
+    });
+}
+
+/// Exports every waiting span. A batch that cannot be exported is dropped.
+pub async fn flush() {
+    let Some(settings) = SETTINGS.get() else {
+        return;
+    };
+    let spans = std::mem::take(&mut *QUEUE.lock().unwrap());
+    if spans.is_empty() {
+        return;
+    }
+    let count = spans.len();
+    let batch = json!({
+        "resourceSpans": [{
+            "resource": {"attributes": [attribute("service.name", &logging::service().into())]},
+            "scopeSpans": [{
+                "scope": {"name": logging::service()},
+                "spans": spans.iter().map(Span::to_otlp).collect::<Vec<_>>(),
+            }],
+        }],
+    });
+    let exported = match settings.exporter {
+        Exporter::None => Ok(()),
+        Exporter::File => export_to_file(settings, &batch),
+        Exporter::Otlp => export_to_collector(settings, &batch).await,
+    };
+    if let Err(e) = exported {
+        logging::warn("span export failed", json!({"spans": count, "error": e.to_string()}));
+    }
+}
+
+fn export_to_file(settings: &TracingSettings, batch: &Value) -> anyhow::Result<()> {
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&settings.file)?;
+    writeln!(file, "{}", batch)?;
+    Ok(())
+}
+
+async fn export_to_collector(settings: &TracingSettings, batch: &Value) -> anyhow::Result<()> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(&settings.otlp_endpoint)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(batch.to_string()))?;
+    let response = tokio::time::timeout(EXPORT_TIMEOUT, Client::new().request(request)).await??;
+    if !response.status().is_success() {
+        anyhow::bail!("the collector answered {}", response.status());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HeaderValue;
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN_ID: &str = "00f067aa0ba902b7";
+
+    fn context(headers: &[(&'static str, &str)]) -> Option<TraceContext> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        TraceContext::from_headers(&map)
+    }
+
+    fn traceparent(traceparent: &str) -> Option<TraceContext> {
+        context(&[(TRACEPARENT, traceparent)])
+    }
+
+    #[test]
+    fn a_valid_traceparent_is_read() {
+        let context = traceparent(&format!("00-{}-{}-01", TRACE_ID, SPAN_ID)).unwrap();
+        assert_eq!(context.trace_id(), TRACE_ID);
+        assert_eq!(context.span_id, 0x00f0_67aa_0ba9_02b7);
+        assert!(context.sampled);
+        assert_eq!(context.state, None);
+        assert_eq!(context.traceparent(), format!("00-{}-{}-01", TRACE_ID, SPAN_ID));
+    }
+
+    #[test]
+    fn only_the_sampled_flag_is_read_from_the_flags() {
+        assert!(!traceparent(&format!("00-{}-{}-00", TRACE_ID, SPAN_ID)).unwrap().sampled);
+        assert!(!traceparent(&format!("00-{}-{}-02", TRACE_ID, SPAN_ID)).unwrap().sampled);
+        assert!(traceparent(&format!("00-{}-{}-03", TRACE_ID, SPAN_ID)).unwrap().sampled);
+    }
+
+    #[test]
+    fn later_versions_may_add_fields() {
+        assert!(traceparent(&format!("01-{}-{}-01-extra", TRACE_ID, SPAN_ID)).is_some());
+        assert!(traceparent(&format!("00-{}-{}-01-extra", TRACE_ID, SPAN_ID)).is_none());
+    }
+
+    #[test]
+    fn an_invalid_traceparent_is_ignored() {
+        assert!(context(&[]).is_none());
+        for invalid in [
+            format!("ff-{}-{}-01", TRACE_ID, SPAN_ID),
+            format!("00-{}-{}-01", "0".repeat(32), SPAN_ID),
+            format!("00-{}-{}-01", TRACE_ID, "0".repeat(16)),
+            format!("00-{}-{}-01", TRACE_ID.to_uppercase(), SPAN_ID),
+            format!("00-{}-{}-01", &TRACE_ID[1..], SPAN_ID),
+            format!("00-{}-{}-1", TRACE_ID, SPAN_ID),
+            format!("00-{}-{}", TRACE_ID, SPAN_ID),
+            "garbage".to_owned(),
+        ] {
+            assert!(traceparent(&invalid).is_none(), "{:?} was read", invalid);
+        }
+    }
+
+    #[test]
+    fn tracestate_headers_are_joined_into_one_list() {
+        let parent = format!("00-{}-{}-01", TRACE_ID, SPAN_ID);
+        let joined = context(&[(TRACEPARENT, &parent), (TRACESTATE, "a=1"), (TRACESTATE, "b=2")]).unwrap();
+        assert_eq!(joined.state.as_deref(), Some("a=1,b=2"));
+
+        let long = format!("a={}", "x".repeat(520));
+        assert_eq!(context(&[(TRACEPARENT, &parent), (TRACESTATE, &long)]).unwrap().state, None);
+        assert!(context(&[(TRACESTATE, "a=1")]).is_none());
+    }
+}